@@ -1,28 +1,56 @@
 use std::fs;
 use std::path::PathBuf;
-use autovideo_core::{process_videos, Mode};
-use clap::Parser;
+use autovideo_core::{plan_videos, process_videos, AspectMode, AudioFormat, CancellationToken, ConversionJob, EncoderSpeed, GridLayout, JobOptions, Mode, ScreenSizes, TextureFormat, TextureOptions, VideoJob};
+use clap::{Parser, Subcommand, ValueEnum};
 
 /// CLI application to automatically make textures, .esp and .nif files for a VotW mod.
 /// 
 /// To start make sure you have a video ready, run the application with a mod name of your choice and the name of your video.
 /// To add additional videos to an esp, use the --esp (and --desp) flag,
 /// not doing this will create a new esp (and overwrite the old one if it's still in the output directory)
-/// An esp can only support up to 10 videos, trying to add more will still make the textures and meshes, but the esp won't be able to update anymore.
+/// To rerun the same conversion later, describe it in a manifest file and pass it with --manifest.
 /// Make sure you have ffmpeg installed.
 #[derive(Parser)]
-#[command(version, verbatim_doc_comment)]
+#[command(version, verbatim_doc_comment, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(flatten)]
+    args: Args,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Probe the videos and print their length, grids, screens and output size without converting anything
+    ///
+    /// Takes the same arguments as a conversion. Also checks the video names and esps, so problems show up before ffmpeg runs. Needs ffprobe.
+    #[command(visible_alias = "dry-run")]
+    Plan(Args),
+}
+
+#[derive(clap::Args)]
 struct Args {
-    /// Name of the mod. At most 10 character!
-    mod_name: String,
+    /// Name of the mod, shown in-game on the holotapes and reels
+    #[arg(required_unless_present_any = ["manifest", "estimate"])]
+    mod_name: Option<String>,
 
     /// Path to video or folder of videos to convert.
     /// 
     /// Names of video files will be used to name the holotapes. In case of single video file, name can be overwritten using "-n".
-    #[arg(short, long)]
-    input: PathBuf,
+    #[arg(short, long, required_unless_present_any = ["manifest", "estimate"])]
+    input: Option<PathBuf>,
+
+    /// TOML or JSON file describing the whole conversion, replaces the mod name, input and conversion options
+    ///
+    /// Holds the mod name, a list of videos with their path and optional name, framerate, trim range and aspect,
+    /// and the shared options like size and texture format. Relative paths are relative to the manifest.
+    #[arg(short, long, value_name = "MANIFEST FILE", conflicts_with_all = [
+        "mod_name", "input", "video_name", "input_esp", "input_esp_drive_in", "size", "tv_size", "projector_size", "drive_in_size", "layout", "keep_aspect_ratio", "aspect", "pad_color", "generate_script", "framerate", "quality", "format", "encoder_speed", "mipmaps", "pcm_audio"
+    ])]
+    manifest: Option<PathBuf>,
 
-    /// Name to use for this video, overwrites name of input video. At most 10 character!
+    /// Name to use for this video, overwrites name of input video
     /// 
     /// This option is ignored when "--input" is a folder.
     #[arg(short = 'n', long)]
@@ -45,18 +73,48 @@ struct Args {
     /// Determines video resolution in-game. Switch to 256 in case you want to preserve drive space.
     #[arg(short, long, default_value_t = 512)]
     size: u32,
+
+    /// Size of the frames shown on TVs, instead of "--size"
+    ///
+    /// Screens with a size of their own get separate grid textures.
+    #[arg(long, value_name = "SIZE")]
+    tv_size: Option<u32>,
+
+    /// Size of the frames shown on projectors, instead of "--size"
+    #[arg(long, value_name = "SIZE")]
+    projector_size: Option<u32>,
+
+    /// Size of the frames shown on the DriveIn screen, instead of "--size"
+    ///
+    /// The DriveIn screen is huge, 1024 keeps it sharp while TVs can stay at a smaller size.
+    #[arg(long, value_name = "SIZE")]
+    drive_in_size: Option<u32>,
+
+    /// Frames per row and column of the grid textures, as COLUMNSxROWS
+    ///
+    /// Fewer frames per grid allow larger frames, more frames per grid make fewer textures. Grid textures can be at most 16384 pixels wide and high.
+    #[arg(short, long, default_value_t = GridLayout::default(), value_name = "COLUMNSxROWS")]
+    layout: GridLayout,
     
     /// Will automatically refit input to 4:3 aspect ratio. (Which fits FO4 TVs better)
-    #[arg(short, long)]
+    ///
+    /// Same as "--aspect pad".
+    #[arg(short, long, conflicts_with = "aspect")]
     keep_aspect_ratio: bool,
-    
-    /// Enable to not give a warning for names being too long and to automatically cut them shorter
-    #[arg(long)]
-    short_names: bool,
+
+    /// How to fit videos into the frames
+    ///
+    /// All modes except stretch make 4:3 frames, which fit FO4 TVs better and let every grid hold more rows of frames.
+    #[arg(short, long, value_enum, default_value_t = Aspect::Stretch)]
+    aspect: Aspect,
+
+    /// Colour of the bars added by "--aspect pad", as a hex colour like 000000
+    #[arg(long, value_name = "RRGGBB", value_parser = parse_color, default_value = "000000")]
+    pad_color: [u8; 3],
     
     /// For advanced users. Generates a FO4Edit script to add video records to existing esp. No esps will be generated
     /// 
-    /// Useful for when you already have an existing VotW esp, either a full one made by autovideo or one you made yourself
+    /// Useful for when your existing VotW esp can't be read by --esp
     #[arg(short, long)]
     generate_script: bool,
     
@@ -72,21 +130,194 @@ struct Args {
 
     /// Enable High Quality
     ///
-    /// High Quality will result in better visuals but double the filesize and take longer to process
-    #[arg(short, long)]
+    /// High Quality will result in better visuals but double the filesize and take longer to process. Same as "--format bc7".
+    #[arg(short, long, conflicts_with = "format")]
     quality: bool,
+
+    /// Compression of the grid textures
+    ///
+    /// Run with "--estimate" to compare the size and encoding time of a grid in every format.
+    #[arg(short = 'f', long, value_enum, default_value_t = Format::Bc1)]
+    format: Format,
+
+    /// How long the texture encoder searches for the best result
+    ///
+    /// Use fast for quick test builds, BC7 in particular gets many times faster.
+    #[arg(long, value_enum, default_value_t = Speed::Slow)]
+    encoder_speed: Speed,
+
+    /// Generate mipmaps for the grid textures
+    ///
+    /// Screens seen from afar flicker less, but the textures get a third larger.
+    #[arg(long)]
+    mipmaps: bool,
+
+    /// Print the size and encoding time of a grid texture in every format for the given size and layout, then exit
+    #[arg(long)]
+    estimate: bool,
+
+    /// Keep the audio as uncompressed PCM instead of ADPCM
+    ///
+    /// Four times the size, only useful if the compressed audio causes problems
+    #[arg(long)]
+    pcm_audio: bool,
+
+    /// Folder to write the mod files to [default: output]
+    ///
+    /// Point this at a mod manager staging folder to skip copying the files over
+    #[arg(short, long, value_name = "FOLDER")]
+    output: Option<PathBuf>,
+
+    /// Amount of videos to convert at the same time [default: 2]
+    ///
+    /// While ffmpeg decodes one video the textures of another are encoded. Higher values mostly use more memory.
+    #[arg(short = 'j', long, value_name = "VIDEOS")]
+    parallel: Option<usize>,
+
+    /// Folder for temporary files, defaults to the system temp folder
+    #[arg(long, value_name = "FOLDER")]
+    cache: Option<PathBuf>,
+
+    /// Convert every video again, even those that have not changed since they were converted into the output folder
+    #[arg(long)]
+    rebuild: bool,
+
+    /// Pack the textures, meshes and sounds into BA2 archives instead of leaving loose files
+    ///
+    /// The archives are named after the esp so the game loads them with it. Running again with "--esp" adds to existing archives.
+    #[arg(long)]
+    ba2: bool,
+
+    /// Write a FOMOD installer into the output folder
+    ///
+    /// Lets mod managers install the TV and Projector files with or without DriveIn. Textures are placed in a folder per size, running again with another size into the same output folder adds it as a texture quality option.
+    #[arg(long)]
+    fomod: bool,
+}
+
+#[derive(Clone, ValueEnum)]
+enum Aspect {
+    /// Stretch the video over square frames
+    Stretch,
+    /// Add bars to fit the whole video
+    Pad,
+    /// Cut off the sides or the top and bottom
+    Crop,
+    /// Crop, following the most detailed part of the video
+    SmartCrop,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    /// Smallest, blocky on gradients
+    Bc1,
+    /// BC1 with an alpha channel, twice the size
+    Bc3,
+    /// Best looking compression, twice the size of BC1 and slow to encode
+    Bc7,
+    /// No compression, eight times the size of BC1
+    Uncompressed,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Speed {
+    Fast,
+    Normal,
+    Slow,
 }
 
+fn parse_color(value: &str) -> Result<[u8; 3], String> {
+    let hex = value.trim_start_matches('#');
+    match u32::from_str_radix(hex, 16) {
+        Ok(color) if hex.len() == 6 => Ok([(color >> 16) as u8, (color >> 8) as u8, color as u8]),
+        _ => Err(format!("{value} is not a hex colour like 000000"))
+    }
+}
 
 fn main() -> Result<(), String> {
-    let args = Args::parse();
+    let cli = Cli::parse();
+    let (args, plan) = match cli.command {
+        Some(Command::Plan(args)) => (args, true),
+        None => (cli.args, false),
+    };
+    let mode = if args.yes { Mode::YES } else { Mode::NO };
+    let (output, cache, parallel, rebuild, ba2, fomod) = (args.output.clone(), args.cache.clone(), args.parallel, args.rebuild, args.ba2, args.fomod);
+
+    if args.estimate {
+        let options = match &args.manifest {
+            Some(manifest) => ConversionJob::from_manifest(manifest).map_err(|e| e.to_string())?.options,
+            None => JobOptions { size: args.size, layout: args.layout, texture: texture_options(&args), ..JobOptions::default() }
+        };
+        let (width, height) = options.layout.texture_size(options.size);
+        println!("Grid textures of {width}x{height}:");
+        for format in TextureFormat::ALL {
+            let options = JobOptions { texture: TextureOptions { format, ..options.texture }, ..options.clone() };
+            println!("  {:<13}{}", format!("{format:?}"), describe_estimate(&options));
+        }
+        return Ok(());
+    }
+
+    let mut job = match &args.manifest {
+        Some(manifest) => ConversionJob::from_manifest(manifest).map_err(|e| e.to_string())?,
+        None => job_from_args(args)?
+    };
+    if let Some(output) = output {
+        job.options.output.output_dir = output;
+    }
+    if let Some(cache) = cache {
+        job.options.output.cache_dir = cache;
+    }
+    if let Some(parallel) = parallel {
+        job.options.parallel_videos = parallel;
+    }
+    if rebuild {
+        job.options.output.incremental = false;
+    }
+    if ba2 {
+        job.options.output.archives = true;
+    }
+    if fomod {
+        job.options.output.fomod = true;
+    }
+
+    if plan {
+        let plan = plan_videos(&job).map_err(|e| e.to_string())?;
+        for video in &plan.videos {
+            let input_framerate = video.input_framerate.map_or(String::new(), |rate| format!(", from {rate:.2} fps"));
+            let trimmed = if video.trimmed { format!(" of {}", format_duration(video.input_duration)) } else { String::new() };
+            println!("\"{}\": {}{trimmed} at {} fps{input_framerate}", video.name, format_duration(video.duration), video.framerate);
+            println!("  {} frames in {} grids", video.frames, video.grids);
+            for screen in &video.screens {
+                match screen.max_duration {
+                    Some(max_duration) if screen.truncated => println!("  Too long for the {}, which plays videos up to {}", screen.screen.name(), format_duration(max_duration)),
+                    _ => println!("  Plays on the {}", screen.screen.name()),
+                }
+            }
+            println!("  About {:.1} MB of textures and {:.1} MB of audio", megabytes(video.texture_bytes), megabytes(video.audio_bytes));
+        }
+        println!("\nTotal: about {:.1} MB of textures and {:.1} MB of audio", megabytes(plan.texture_bytes), megabytes(plan.audio_bytes));
+        return Ok(());
+    }
+
+    println!("Grid textures: {:?}, {}", job.options.texture.format, describe_estimate(&job.options));
+    process_videos(&job, mode, &CancellationToken::new(), |_| {}).map_err(|e| e.to_string())?;
+    
+    Ok(())
+}
+
+fn job_from_args(args: Args) -> Result<ConversionJob, String> {
+    let texture = texture_options(&args);
+    // Both are required by clap when there is no manifest
+    let (Some(mod_name), Some(input)) = (args.mod_name, args.input) else {
+        return Err("Missing mod name or input".to_string());
+    };
 
     let mut inputs = vec![];
-    if args.input.exists() {
-        if args.input.is_file() {
-            inputs.push(args.input);
-        } else if args.input.is_dir() {
-            for input in fs::read_dir(args.input).unwrap().flatten() {
+    if input.exists() {
+        if input.is_file() {
+            inputs.push(input);
+        } else if input.is_dir() {
+            for input in fs::read_dir(input).unwrap().flatten() {
                 let path = input.path();
                 if path.is_file() {
                     inputs.push(path);
@@ -94,25 +325,64 @@ fn main() -> Result<(), String> {
             }
         }
     } else {
-        return Err(format!("File or folder does not exist: {}", &args.input.to_str().unwrap()));
+        return Err(format!("File or folder does not exist: {}", input.display()));
     }
-    
-    process_videos(
-        inputs,
-        args.input_esp,
-        args.input_esp_drive_in,
-        args.mod_name,
-        args.framerate,
-        args.short_names,
-        args.video_name,
-        args.size,
-        args.keep_aspect_ratio,
-        args.generate_script,
-        None,
-        if args.yes { Mode::YES } else { Mode::NO },
-        || {},
-        args.quality
-    )?;
-    
-    Ok(())
+
+    let mut videos: Vec<VideoJob> = inputs.into_iter().map(VideoJob::new).collect();
+    if let ([video], Some(video_name)) = (videos.as_mut_slice(), args.video_name) {
+        video.name = Some(video_name);
+    }
+
+    ConversionJob::builder(mod_name)
+        .videos(videos)
+        .input_esp(args.input_esp)
+        .input_esp_drive_in(args.input_esp_drive_in)
+        .framerate(args.framerate)
+        .size(args.size)
+        .screen_sizes(ScreenSizes { television: args.tv_size, projector: args.projector_size, drive_in: args.drive_in_size })
+        .layout(args.layout)
+        .aspect(match args.aspect {
+            _ if args.keep_aspect_ratio => AspectMode::Pad { color: args.pad_color },
+            Aspect::Stretch => AspectMode::Stretch,
+            Aspect::Pad => AspectMode::Pad { color: args.pad_color },
+            Aspect::Crop => AspectMode::Crop,
+            Aspect::SmartCrop => AspectMode::SmartCrop,
+        })
+        .generate_script(args.generate_script)
+        .texture(texture)
+        .audio_format(if args.pcm_audio { AudioFormat::Pcm } else { AudioFormat::Adpcm })
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+fn texture_options(args: &Args) -> TextureOptions {
+    TextureOptions {
+        format: match args.format {
+            _ if args.quality => TextureFormat::Bc7,
+            Format::Bc1 => TextureFormat::Bc1,
+            Format::Bc3 => TextureFormat::Bc3,
+            Format::Bc7 => TextureFormat::Bc7,
+            Format::Uncompressed => TextureFormat::Uncompressed,
+        },
+        speed: match args.encoder_speed {
+            Speed::Fast => EncoderSpeed::Fast,
+            Speed::Normal => EncoderSpeed::Normal,
+            Speed::Slow => EncoderSpeed::Slow,
+        },
+        mipmaps: args.mipmaps,
+    }
+}
+
+fn describe_estimate(options: &JobOptions) -> String {
+    let estimate = options.texture_estimate();
+    format!("about {:.1} MB and {:.1} seconds per grid", megabytes(estimate.bytes), estimate.seconds)
+}
+
+fn megabytes(bytes: u64) -> f64 {
+    bytes as f64 / 1_048_576.0
+}
+
+/// Seconds as `MM:SS.S`
+fn format_duration(seconds: f64) -> String {
+    format!("{:02}:{:04.1}", (seconds / 60.0) as u32, seconds % 60.0)
 }
@@ -0,0 +1,140 @@
+use image::{imageops, imageops::FilterType, GrayImage, RgbaImage};
+use serde::{Deserialize, Serialize};
+use crate::grid::GridGeometry;
+
+/// How much a smart crop moves towards the best position every frame, so it pans instead of jumping around
+const SMART_CROP_SMOOTHING: f32 = 0.2;
+/// Smart crops measure detail on a smaller copy of the frame, this many pixels go into one
+const SMART_CROP_STEP: u32 = 4;
+
+/// How videos that don't have the shape of their frames are fit into them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum AspectMode {
+    /// Stretches the video over square frames, the 4:3 screens in-game stretch it once more
+    #[default]
+    Stretch,
+    /// Fits the whole video in 4:3 frames, filling the rest with bars of an RGB colour
+    Pad {
+        #[serde(default)]
+        color: [u8; 3],
+    },
+    /// Fills 4:3 frames by cutting off the sides, or the top and bottom, of the video
+    Crop,
+    /// Like crop, but follows the most detailed part of the video instead of staying in the center
+    SmartCrop,
+}
+
+impl AspectMode {
+    /// Whether frames are 4:3 instead of square
+    pub fn four_by_three(&self) -> bool {
+        *self != AspectMode::Stretch
+    }
+
+    /// Size to scale a video of the given size to before it is fit into a frame
+    pub fn scaled_size(&self, (width, height): (u32, u32), (frame_width, frame_height): (u32, u32)) -> (u32, u32) {
+        let (width_scale, height_scale) = (frame_width as f64 / width as f64, frame_height as f64 / height as f64);
+        let scale = match self {
+            AspectMode::Stretch => return (frame_width, frame_height),
+            AspectMode::Pad { .. } => width_scale.min(height_scale),
+            AspectMode::Crop | AspectMode::SmartCrop => width_scale.max(height_scale),
+        };
+        let scaled = |size: u32| ((size as f64 * scale).round() as u32).max(1);
+        let (width, height) = (scaled(width), scaled(height));
+        // Rounding may not leave the scaled side exactly at the frame size
+        match self {
+            AspectMode::Pad { .. } => (width.min(frame_width), height.min(frame_height)),
+            _ => (width.max(frame_width), height.max(frame_height)),
+        }
+    }
+}
+
+/// Brings frames to the size of the frames on the grids, one after another as they are added to the grids
+pub struct FrameFitter {
+    mode: AspectMode,
+    frame_width: u32,
+    frame_height: u32,
+    /// Position of the last smart crop along the cropped side
+    crop_offset: Option<f32>,
+}
+
+impl FrameFitter {
+    pub fn new(mode: AspectMode, geometry: &GridGeometry) -> Self {
+        FrameFitter { mode, frame_width: geometry.frame_width, frame_height: geometry.frame_height, crop_offset: None }
+    }
+
+    /// Pads or crops a frame to the frame size. Frames that were not scaled by [`AspectMode::scaled_size`] yet are scaled first
+    pub fn fit(&mut self, frame: RgbaImage) -> RgbaImage {
+        let (frame_width, frame_height) = (self.frame_width, self.frame_height);
+        let scaled_size = self.mode.scaled_size(frame.dimensions(), (frame_width, frame_height));
+        let frame = if frame.dimensions() == scaled_size {
+            frame
+        } else {
+            imageops::resize(&frame, scaled_size.0, scaled_size.1, FilterType::Triangle)
+        };
+        let (width, height) = frame.dimensions();
+        match self.mode {
+            _ if (width, height) == (frame_width, frame_height) => frame,
+            AspectMode::Stretch => frame,
+            AspectMode::Pad { color: [red, green, blue] } => {
+                let mut padded = RgbaImage::from_pixel(frame_width, frame_height, [red, green, blue, 255].into());
+                imageops::replace(&mut padded, &frame, ((frame_width - width) / 2) as i64, ((frame_height - height) / 2) as i64);
+                padded
+            }
+            AspectMode::Crop => {
+                imageops::crop_imm(&frame, (width - frame_width) / 2, (height - frame_height) / 2, frame_width, frame_height).to_image()
+            }
+            AspectMode::SmartCrop => {
+                let horizontal = width > frame_width;
+                let (size, window) = if horizontal { (width, frame_width) } else { (height, frame_height) };
+                let best = detailed_window(&frame, horizontal, window) as f32;
+                let offset = match self.crop_offset {
+                    Some(offset) => offset + (best - offset) * SMART_CROP_SMOOTHING,
+                    None => best
+                };
+                self.crop_offset = Some(offset);
+                let offset = (offset.round() as u32).min(size - window);
+                let (x, y) = if horizontal { (offset, 0) } else { (0, offset) };
+                imageops::crop_imm(&frame, x, y, frame_width, frame_height).to_image()
+            }
+        }
+    }
+}
+
+/// Start of the window along the width or height of a frame with the most edges in it
+fn detailed_window(frame: &RgbaImage, horizontal: bool, window: u32) -> u32 {
+    let (width, height) = frame.dimensions();
+    let small = imageops::grayscale(&imageops::resize(
+        frame,
+        (width / SMART_CROP_STEP).max(2),
+        (height / SMART_CROP_STEP).max(2),
+        FilterType::Triangle
+    ));
+    let detail = line_detail(&small, horizontal);
+    let window = ((window / SMART_CROP_STEP) as usize).clamp(1, detail.len());
+    let mut sum: u64 = detail[..window].iter().sum();
+    let (mut best, mut best_sum) = (0, sum);
+    for start in 1..=detail.len() - window {
+        sum = sum + detail[start + window - 1] - detail[start - 1];
+        if sum > best_sum {
+            (best, best_sum) = (start, sum);
+        }
+    }
+    best as u32 * SMART_CROP_STEP
+}
+
+/// Sum of the brightness differences between neighbouring pixels in every column, or every row when not `horizontal`
+fn line_detail(image: &GrayImage, horizontal: bool) -> Vec<u64> {
+    let (width, height) = image.dimensions();
+    let mut detail = vec![0; if horizontal { width } else { height } as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let value = image.get_pixel(x, y)[0] as i32;
+            let right = image.get_pixel((x + 1).min(width - 1), y)[0] as i32;
+            let below = image.get_pixel(x, (y + 1).min(height - 1))[0] as i32;
+            let line = if horizontal { x } else { y };
+            detail[line as usize] += (value.abs_diff(right) + value.abs_diff(below)) as u64;
+        }
+    }
+    detail
+}
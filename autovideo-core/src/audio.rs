@@ -0,0 +1,268 @@
+use std::fs;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::error::{AutovideoError, PathContext, Result};
+
+/// Encoding of the generated sound files. Fallout 4 reads Microsoft ADPCM and PCM wav files
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioFormat {
+    /// 4 bit Microsoft ADPCM (`WAVE_FORMAT_ADPCM`, 1024 byte blocks), a quarter of the size of PCM
+    #[default]
+    Adpcm,
+    /// Uncompressed 16 bit PCM, as extracted from the video
+    Pcm,
+}
+
+impl AudioFormat {
+    /// Size of the sound file of a mono input with the given sample rate and length in seconds
+    pub fn wav_size(&self, sample_rate: u32, duration: f64) -> u64 {
+        let samples = (sample_rate as f64 * duration).ceil() as u64;
+        match self {
+            // RIFF header, fmt and data chunk
+            AudioFormat::Pcm => 44 + samples * 2,
+            // RIFF header, fmt chunk with the coefficients, fact and data chunk
+            AudioFormat::Adpcm => 90 + samples.div_ceil(SAMPLES_PER_BLOCK as u64) * BLOCK_ALIGN as u64,
+        }
+    }
+}
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_ADPCM: u16 = 2;
+const BLOCK_ALIGN: usize = 1024;
+/// Two samples are stored uncompressed in the block header, the rest as one nibble each
+const SAMPLES_PER_BLOCK: usize = (BLOCK_ALIGN - 7) * 2 + 2;
+const ADAPTATION_TABLE: [i32; 16] = [230, 230, 230, 230, 307, 409, 512, 614, 768, 614, 512, 409, 307, 230, 230, 230];
+const COEFFICIENTS: [(i32, i32); 7] = [(256, 0), (512, -256), (0, 0), (192, 64), (240, 0), (460, -208), (392, -232)];
+
+/// Re-encodes a 16 bit mono PCM wav in place
+pub fn encode_wav(path: &Path, format: AudioFormat) -> Result<()> {
+    if format == AudioFormat::Pcm {
+        return Ok(());
+    }
+    let invalid = |message: &str| AutovideoError::Audio { path: path.to_path_buf(), message: message.to_string() };
+    let bytes = fs::read(path).at(path)?;
+    let (rate, samples) = read_pcm_wav(&bytes).ok_or_else(|| invalid("Not a 16 bit mono PCM wav"))?;
+    fs::write(path, adpcm_wav(rate, &samples)).at(path)
+}
+
+/// Sample rate and samples of a 16 bit mono PCM wav
+fn read_pcm_wav(bytes: &[u8]) -> Option<(u32, Vec<i16>)> {
+    if bytes.get(0..4)? != b"RIFF" || bytes.get(8..12)? != b"WAVE" {
+        return None;
+    }
+    let mut rate = None;
+    let mut position = 12;
+    while position + 8 <= bytes.len() {
+        let id = &bytes[position..position + 4];
+        let size = u32::from_le_bytes(bytes[position + 4..position + 8].try_into().ok()?) as usize;
+        let data = bytes.get(position + 8..(position + 8 + size).min(bytes.len()))?;
+        match id {
+            b"fmt " => {
+                let format = u16::from_le_bytes(data.get(0..2)?.try_into().ok()?);
+                let channels = u16::from_le_bytes(data.get(2..4)?.try_into().ok()?);
+                let bits = u16::from_le_bytes(data.get(14..16)?.try_into().ok()?);
+                if format != WAVE_FORMAT_PCM || channels != 1 || bits != 16 {
+                    return None;
+                }
+                rate = Some(u32::from_le_bytes(data.get(4..8)?.try_into().ok()?));
+            }
+            b"data" => {
+                let samples = data.chunks_exact(2).map(|sample| i16::from_le_bytes([sample[0], sample[1]])).collect();
+                return Some((rate?, samples));
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even size
+        position += 8 + size + (size & 1);
+    }
+    None
+}
+
+fn adpcm_wav(rate: u32, samples: &[i16]) -> Vec<u8> {
+    let blocks: Vec<u8> = samples.chunks(SAMPLES_PER_BLOCK).flat_map(encode_block).collect();
+
+    let byte_rate = (rate as u64 * BLOCK_ALIGN as u64 / SAMPLES_PER_BLOCK as u64) as u32;
+    let mut format = Vec::with_capacity(50);
+    format.extend(WAVE_FORMAT_ADPCM.to_le_bytes());
+    format.extend(1u16.to_le_bytes());
+    format.extend(rate.to_le_bytes());
+    format.extend(byte_rate.to_le_bytes());
+    format.extend((BLOCK_ALIGN as u16).to_le_bytes());
+    format.extend(4u16.to_le_bytes());
+    format.extend(32u16.to_le_bytes());
+    format.extend((SAMPLES_PER_BLOCK as u16).to_le_bytes());
+    format.extend((COEFFICIENTS.len() as u16).to_le_bytes());
+    for (coefficient1, coefficient2) in COEFFICIENTS {
+        format.extend((coefficient1 as i16).to_le_bytes());
+        format.extend((coefficient2 as i16).to_le_bytes());
+    }
+
+    let mut wav = Vec::with_capacity(blocks.len() + 90);
+    wav.extend(b"RIFF");
+    wav.extend((4 + 8 + format.len() as u32 + 12 + 8 + blocks.len() as u32).to_le_bytes());
+    wav.extend(b"WAVE");
+    let chunks: [(&[u8; 4], &[u8]); 3] = [(b"fmt ", &format), (b"fact", &(samples.len() as u32).to_le_bytes()), (b"data", &blocks)];
+    for (id, data) in chunks {
+        wav.extend(id);
+        wav.extend((data.len() as u32).to_le_bytes());
+        wav.extend(data);
+    }
+    wav
+}
+
+/// Encodes up to `SAMPLES_PER_BLOCK` samples into a full block, with the predictor that fits them best
+fn encode_block(samples: &[i16]) -> Vec<u8> {
+    let mut padded = samples.to_vec();
+    padded.resize(SAMPLES_PER_BLOCK, 0);
+    (0..COEFFICIENTS.len())
+        .map(|predictor| encode_block_with(&padded, predictor))
+        .min_by_key(|(_, error)| *error)
+        .map(|(block, _)| block)
+        .unwrap()
+}
+
+/// The encoded block and its squared error
+fn encode_block_with(samples: &[i16], predictor: usize) -> (Vec<u8>, u64) {
+    let (coefficient1, coefficient2) = COEFFICIENTS[predictor];
+    let (mut sample2, mut sample1) = (samples[0] as i32, samples[1] as i32);
+    let predict = |sample1: i32, sample2: i32| (sample1 * coefficient1 + sample2 * coefficient2) >> 8;
+    // Start with a step size that fits the first few prediction errors
+    let initial_error: i32 = samples[2..]
+        .iter()
+        .take(4)
+        .scan((sample1, sample2), |(sample1, sample2), &sample| {
+            let error = (sample as i32 - predict(*sample1, *sample2)).abs();
+            (*sample2, *sample1) = (*sample1, sample as i32);
+            Some(error)
+        })
+        .sum();
+    let mut delta = (initial_error / 16).max(16);
+
+    let mut block = Vec::with_capacity(BLOCK_ALIGN);
+    block.push(predictor as u8);
+    block.extend((delta as i16).to_le_bytes());
+    block.extend((sample1 as i16).to_le_bytes());
+    block.extend((sample2 as i16).to_le_bytes());
+
+    let mut squared_error = 0u64;
+    let mut high_nibble = None;
+    for &sample in &samples[2..] {
+        let prediction = predict(sample1, sample2);
+        let nibble = ((sample as i32 - prediction) as f64 / delta as f64).round().clamp(-8.0, 7.0) as i32;
+        let decoded = (prediction + nibble * delta).clamp(i16::MIN as i32, i16::MAX as i32);
+        squared_error += ((sample as i32 - decoded) as i64).pow(2) as u64;
+        (sample2, sample1) = (sample1, decoded);
+        let nibble = (nibble & 0xF) as u8;
+        delta = ((ADAPTATION_TABLE[nibble as usize] * delta) >> 8).max(16);
+        match high_nibble.take() {
+            Some(high) => block.push((high << 4) | nibble),
+            None => high_nibble = Some(nibble)
+        }
+    }
+    (block, squared_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The samples of a mono ADPCM wav, decoded the way Microsoft ADPCM decoders do
+    fn decode_adpcm(wav: &[u8]) -> Vec<i16> {
+        let chunk = |id: &[u8; 4]| {
+            let mut position = 12;
+            loop {
+                let size = u32::from_le_bytes(wav[position + 4..position + 8].try_into().unwrap()) as usize;
+                if &wav[position..position + 4] == id {
+                    return &wav[position + 8..position + 8 + size];
+                }
+                position += 8 + size;
+            }
+        };
+        let format = chunk(b"fmt ");
+        assert_eq!(u16::from_le_bytes([format[0], format[1]]), WAVE_FORMAT_ADPCM);
+        assert_eq!(u16::from_le_bytes([format[12], format[13]]) as usize, BLOCK_ALIGN);
+        let sample_amount = u32::from_le_bytes(chunk(b"fact").try_into().unwrap()) as usize;
+
+        let mut samples = vec![];
+        for block in chunk(b"data").chunks(BLOCK_ALIGN) {
+            let (coefficient1, coefficient2) = COEFFICIENTS[block[0] as usize];
+            let mut delta = i16::from_le_bytes([block[1], block[2]]) as i32;
+            let mut sample1 = i16::from_le_bytes([block[3], block[4]]) as i32;
+            let mut sample2 = i16::from_le_bytes([block[5], block[6]]) as i32;
+            samples.extend([sample2 as i16, sample1 as i16]);
+            for nibble in block[7..].iter().flat_map(|byte| [byte >> 4, byte & 0xF]) {
+                let signed = if nibble & 8 != 0 { nibble as i32 - 16 } else { nibble as i32 };
+                let prediction = (sample1 * coefficient1 + sample2 * coefficient2) >> 8;
+                let sample = (prediction + signed * delta).clamp(i16::MIN as i32, i16::MAX as i32);
+                samples.push(sample as i16);
+                (sample2, sample1) = (sample1, sample);
+                delta = ((ADAPTATION_TABLE[nibble as usize] * delta) >> 8).max(16);
+            }
+        }
+        samples.truncate(sample_amount);
+        samples
+    }
+
+    fn pcm_wav(rate: u32, samples: &[i16]) -> Vec<u8> {
+        let data: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        let mut wav = b"RIFF".to_vec();
+        wav.extend((36 + data.len() as u32).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(WAVE_FORMAT_PCM.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(rate.to_le_bytes());
+        wav.extend((rate * 2).to_le_bytes());
+        wav.extend(2u16.to_le_bytes());
+        wav.extend(16u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend((data.len() as u32).to_le_bytes());
+        wav.extend(data);
+        wav
+    }
+
+    /// Two tones and a sudden jump, over several blocks with a partial one at the end
+    fn test_signal(amount: usize) -> Vec<i16> {
+        (0..amount).map(|i| {
+            let time = i as f64 / 44100.0;
+            let tones = 8000.0 * (time * 440.0 * std::f64::consts::TAU).sin() + 4000.0 * (time * 3000.0 * std::f64::consts::TAU).sin();
+            (tones + if i > amount / 2 { 6000.0 } else { 0.0 }) as i16
+        }).collect()
+    }
+
+    #[test]
+    fn adpcm_decodes_close_to_the_pcm_input() {
+        let samples = test_signal(44100);
+        let wav = adpcm_wav(44100, &samples);
+        let decoded = decode_adpcm(&wav);
+        assert_eq!(decoded.len(), samples.len());
+        let signal: f64 = samples.iter().map(|&s| (s as f64).powi(2)).sum();
+        let noise: f64 = samples.iter().zip(&decoded).map(|(&s, &d)| (s as f64 - d as f64).powi(2)).sum();
+        let snr = 10.0 * (signal / noise).log10();
+        assert!(snr > 25.0, "Signal to noise ratio of {snr:.1} dB");
+    }
+
+    #[test]
+    fn encoded_files_have_the_estimated_size() {
+        let dir = std::env::temp_dir().join(format!("autovideo-audio-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("Video.wav");
+        let samples = test_signal(22050 * 3 + 17);
+        fs::write(&path, pcm_wav(22050, &samples)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), AudioFormat::Pcm.wav_size(22050, samples.len() as f64 / 22050.0));
+        encode_wav(&path, AudioFormat::Adpcm).unwrap();
+        let wav = fs::read(&path).unwrap();
+        assert_eq!(wav.len() as u64, AudioFormat::Adpcm.wav_size(22050, samples.len() as f64 / 22050.0));
+        assert_eq!(decode_adpcm(&wav).len(), samples.len());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_mono_pcm_is_encoded() {
+        let mut stereo = pcm_wav(44100, &[0; 8]);
+        stereo[22] = 2;
+        assert!(read_pcm_wav(&stereo).is_none());
+        assert_eq!(read_pcm_wav(&pcm_wav(44100, &[1, -1])), Some((44100, vec![1, -1])));
+    }
+}
@@ -0,0 +1,438 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use flate2::{write::ZlibEncoder, Compression};
+use crate::error::{AutovideoError, PathContext, Result};
+
+const MAGIC: &[u8; 4] = b"BTDX";
+const VERSION: u32 = 1;
+/// Versions of the next-gen update, which only differ in their version number
+const SUPPORTED_VERSIONS: [u32; 3] = [1, 7, 8];
+const HEADER_SIZE: u64 = 24;
+const GENERAL_RECORD_SIZE: u64 = 36;
+const TEXTURE_RECORD_SIZE: u64 = 24;
+const CHUNK_SIZE: u64 = 24;
+const GENERAL_FLAGS: u32 = 0x0010_0100;
+const TEXTURE_FLAGS: u16 = 0x0800;
+const ALIGNMENT: u32 = 0xBAAD_F00D;
+/// Stored uncompressed, like the sounds in the game's own archives
+const UNCOMPRESSED_EXTENSIONS: [&str; 3] = ["wav", "xwm", "fuz"];
+
+/// The two kinds of archives Fallout 4 loads: general files, and textures split into chunks of mipmaps
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    General,
+    Textures,
+}
+
+impl ArchiveKind {
+    fn tag(&self) -> &'static [u8; 4] {
+        match self {
+            ArchiveKind::General => b"GNRL",
+            ArchiveKind::Textures => b"DX10",
+        }
+    }
+}
+
+/// Index entry of a file, pointing at its data in the archive
+struct Record {
+    name_hash: u32,
+    extension: [u8; 4],
+    directory_hash: u32,
+    data: RecordData,
+}
+
+enum RecordData {
+    General(Chunk),
+    Texture { height: u16, width: u16, mipmaps: u8, format: u8, flags: u16, chunks: Vec<Chunk> },
+}
+
+/// Stored bytes of a file, or of some mipmaps of a texture. `packed_size` is 0 for uncompressed data
+#[derive(Clone, Copy)]
+struct Chunk {
+    offset: u64,
+    packed_size: u32,
+    unpacked_size: u32,
+    start_mipmap: u16,
+    end_mipmap: u16,
+}
+
+impl Chunk {
+    fn stored_size(&self) -> u64 {
+        if self.packed_size == 0 { self.unpacked_size as u64 } else { self.packed_size as u64 }
+    }
+}
+
+impl Record {
+    fn size(&self) -> u64 {
+        match &self.data {
+            RecordData::General(_) => GENERAL_RECORD_SIZE,
+            RecordData::Texture { chunks, .. } => TEXTURE_RECORD_SIZE + CHUNK_SIZE * chunks.len() as u64,
+        }
+    }
+
+    fn chunks_mut(&mut self) -> &mut [Chunk] {
+        match &mut self.data {
+            RecordData::General(chunk) => std::slice::from_mut(chunk),
+            RecordData::Texture { chunks, .. } => chunks,
+        }
+    }
+}
+
+/// Writes the files to a Fallout 4 archive, given as their path in the archive and their path on disk.
+/// Files of an archive that already exists at `path` are kept, unless they are replaced
+pub fn pack(path: &Path, kind: ArchiveKind, files: &[(String, PathBuf)]) -> Result<()> {
+    let invalid = |message: String| AutovideoError::InvalidArchive { path: path.to_path_buf(), message };
+    let (mut old_reader, kept) = if path.is_file() {
+        let mut reader = BufReader::new(File::open(path).at(path)?);
+        let (records, names) = read_index(&mut reader, kind).map_err(|e| invalid(e.to_string()))?;
+        let kept: Vec<(Record, String)> = records.into_iter().zip(names)
+            .filter(|(_, name)| !files.iter().any(|(new_name, _)| new_name.eq_ignore_ascii_case(name)))
+            .collect();
+        (Some(reader), kept)
+    } else {
+        (None, vec![])
+    };
+    let (mut records, mut names): (Vec<Record>, Vec<String>) = kept.into_iter().unzip();
+    let old_amount = records.len();
+    for (name, _) in files {
+        records.push(new_record(kind, name));
+        names.push(name.clone());
+    }
+
+    let temporary_path = path.with_extension("ba2.tmp");
+    let mut writer = BufWriter::new(File::create(&temporary_path).at(&temporary_path)?);
+    // The index is written last, once the offsets of all data are known
+    let index_size: u64 = records.iter().map(Record::size).sum();
+    writer.write_all(&vec![0; (HEADER_SIZE + index_size) as usize]).at(&temporary_path)?;
+    let mut offset = HEADER_SIZE + index_size;
+    for (index, record) in records.iter_mut().enumerate() {
+        if index < old_amount {
+            let reader = old_reader.as_mut().unwrap();
+            for chunk in record.chunks_mut() {
+                reader.seek(SeekFrom::Start(chunk.offset)).at(path)?;
+                io::copy(&mut reader.by_ref().take(chunk.stored_size()), &mut writer).at(&temporary_path)?;
+                chunk.offset = offset;
+                offset += chunk.stored_size();
+            }
+        } else {
+            let source = &files[index - old_amount].1;
+            let mut data = fs::read(source).at(source)?;
+            if let RecordData::Texture { height, width, mipmaps, format, chunks, .. } = &mut record.data {
+                // Textures are stored without their header, which becomes part of the record
+                let header = DdsHeader::parse(&data).map_err(|message| AutovideoError::Image { path: source.clone(), message })?;
+                (*height, *width, *mipmaps, *format) = (header.height, header.width, header.mipmaps, header.format);
+                chunks[0].end_mipmap = header.mipmaps as u16 - 1;
+                data.drain(..header.size);
+            }
+            let extension = String::from_utf8_lossy(&record.extension).trim_end_matches('\0').to_string();
+            let compressed = if UNCOMPRESSED_EXTENSIONS.contains(&extension.as_str()) { None } else { compress(&data) };
+            let stored = compressed.as_deref().unwrap_or(&data);
+            writer.write_all(stored).at(&temporary_path)?;
+            let chunk = &mut record.chunks_mut()[0];
+            (chunk.offset, chunk.packed_size, chunk.unpacked_size) = (offset, compressed.as_ref().map_or(0, |c| c.len() as u32), data.len() as u32);
+            offset += stored.len() as u64;
+        }
+    }
+    for name in &names {
+        writer.write_all(&(name.len() as u16).to_le_bytes()).at(&temporary_path)?;
+        writer.write_all(name.as_bytes()).at(&temporary_path)?;
+    }
+
+    writer.seek(SeekFrom::Start(0)).at(&temporary_path)?;
+    write_index(&mut writer, kind, &records, offset).at(&temporary_path)?;
+    writer.into_inner().map_err(|e| e.into_error()).at(&temporary_path)?;
+    drop(old_reader);
+    fs::rename(&temporary_path, path).at(path)
+}
+
+/// The index entry of a file that is not written yet. Its data, and for textures their size and format, are filled in while writing
+fn new_record(kind: ArchiveKind, name: &str) -> Record {
+    let (name_hash, extension, directory_hash) = hashes(name);
+    let chunk = Chunk { offset: 0, packed_size: 0, unpacked_size: 0, start_mipmap: 0, end_mipmap: 0 };
+    let data = match kind {
+        ArchiveKind::General => RecordData::General(chunk),
+        // All mipmaps go in a single chunk
+        ArchiveKind::Textures => RecordData::Texture { height: 0, width: 0, mipmaps: 1, format: 0, flags: TEXTURE_FLAGS, chunks: vec![chunk] },
+    };
+    Record { name_hash, extension, directory_hash, data }
+}
+
+/// Zlib compressed data, unless compressing does not make it smaller
+fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).ok()?;
+    encoder.finish().ok().filter(|compressed| compressed.len() < data.len())
+}
+
+/// Hashes of the file name without extension and of the folder, with the extension. All lowercase with backslashes
+fn hashes(path: &str) -> (u32, [u8; 4], u32) {
+    let path = path.to_lowercase().replace('/', "\\");
+    let (directory, file) = path.rsplit_once('\\').unwrap_or(("", &path));
+    let (stem, extension) = file.rsplit_once('.').unwrap_or((file, ""));
+    let mut extension_bytes = [0; 4];
+    for (byte, value) in extension_bytes.iter_mut().zip(extension.bytes()) {
+        *byte = value;
+    }
+    (crc32(stem), extension_bytes, crc32(directory))
+}
+
+/// The CRC-32 Bethesda archives use, which starts at 0 and has no final xor
+fn crc32(value: &str) -> u32 {
+    let mut crc = 0u32;
+    for byte in value.bytes() {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+fn write_index(out: &mut impl Write, kind: ArchiveKind, records: &[Record], name_table_offset: u64) -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    out.write_all(kind.tag())?;
+    out.write_all(&(records.len() as u32).to_le_bytes())?;
+    out.write_all(&name_table_offset.to_le_bytes())?;
+    for record in records {
+        out.write_all(&record.name_hash.to_le_bytes())?;
+        out.write_all(&record.extension)?;
+        out.write_all(&record.directory_hash.to_le_bytes())?;
+        match &record.data {
+            RecordData::General(chunk) => {
+                out.write_all(&GENERAL_FLAGS.to_le_bytes())?;
+                out.write_all(&chunk.offset.to_le_bytes())?;
+                out.write_all(&chunk.packed_size.to_le_bytes())?;
+                out.write_all(&chunk.unpacked_size.to_le_bytes())?;
+                out.write_all(&ALIGNMENT.to_le_bytes())?;
+            }
+            RecordData::Texture { height, width, mipmaps, format, flags, chunks } => {
+                out.write_all(&[0, chunks.len() as u8])?;
+                out.write_all(&(CHUNK_SIZE as u16).to_le_bytes())?;
+                out.write_all(&height.to_le_bytes())?;
+                out.write_all(&width.to_le_bytes())?;
+                out.write_all(&[*mipmaps, *format])?;
+                out.write_all(&flags.to_le_bytes())?;
+                for chunk in chunks {
+                    out.write_all(&chunk.offset.to_le_bytes())?;
+                    out.write_all(&chunk.packed_size.to_le_bytes())?;
+                    out.write_all(&chunk.unpacked_size.to_le_bytes())?;
+                    out.write_all(&chunk.start_mipmap.to_le_bytes())?;
+                    out.write_all(&chunk.end_mipmap.to_le_bytes())?;
+                    out.write_all(&ALIGNMENT.to_le_bytes())?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Names of the files in an existing archive of either kind
+pub fn file_names(path: &Path) -> io::Result<Vec<String>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut header = [0; 12];
+    reader.read_exact(&mut header)?;
+    reader.rewind()?;
+    let kind = if &header[8..12] == ArchiveKind::Textures.tag() { ArchiveKind::Textures } else { ArchiveKind::General };
+    read_index(&mut reader, kind).map(|(_, names)| names)
+}
+
+/// Index and file names of an existing archive of the given kind
+fn read_index(reader: &mut (impl Read + Seek), kind: ArchiveKind) -> io::Result<(Vec<Record>, Vec<String>)> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut header = [0; HEADER_SIZE as usize];
+    reader.read_exact(&mut header)?;
+    let u16_at = |bytes: &[u8], at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+    let u32_at = |bytes: &[u8], at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+    let u64_at = |bytes: &[u8], at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+    if &header[0..4] != MAGIC || !SUPPORTED_VERSIONS.contains(&u32_at(&header, 4)) {
+        return Err(invalid("Not a Fallout 4 archive"));
+    }
+    if &header[8..12] != kind.tag() {
+        return Err(invalid("Archive holds a different kind of files"));
+    }
+    let file_amount = u32_at(&header, 12) as usize;
+    let name_table_offset = u64_at(&header, 16);
+
+    let mut records = Vec::with_capacity(file_amount);
+    for _ in 0..file_amount {
+        let mut record = [0; GENERAL_RECORD_SIZE as usize];
+        let size = if kind == ArchiveKind::General { GENERAL_RECORD_SIZE } else { TEXTURE_RECORD_SIZE };
+        reader.read_exact(&mut record[..size as usize])?;
+        let data = match kind {
+            ArchiveKind::General => RecordData::General(Chunk {
+                offset: u64_at(&record, 16),
+                packed_size: u32_at(&record, 24),
+                unpacked_size: u32_at(&record, 28),
+                start_mipmap: 0,
+                end_mipmap: 0,
+            }),
+            ArchiveKind::Textures => {
+                let mut chunks = vec![];
+                for _ in 0..record[13] {
+                    let mut chunk = [0; CHUNK_SIZE as usize];
+                    reader.read_exact(&mut chunk)?;
+                    chunks.push(Chunk {
+                        offset: u64_at(&chunk, 0),
+                        packed_size: u32_at(&chunk, 8),
+                        unpacked_size: u32_at(&chunk, 12),
+                        start_mipmap: u16_at(&chunk, 16),
+                        end_mipmap: u16_at(&chunk, 18),
+                    });
+                }
+                RecordData::Texture {
+                    height: u16_at(&record, 16),
+                    width: u16_at(&record, 18),
+                    mipmaps: record[20],
+                    format: record[21],
+                    flags: u16_at(&record, 22),
+                    chunks,
+                }
+            }
+        };
+        records.push(Record { name_hash: u32_at(&record, 0), extension: record[4..8].try_into().unwrap(), directory_hash: u32_at(&record, 8), data });
+    }
+
+    reader.seek(SeekFrom::Start(name_table_offset))?;
+    let mut names = Vec::with_capacity(file_amount);
+    for _ in 0..file_amount {
+        let mut length = [0; 2];
+        reader.read_exact(&mut length)?;
+        let mut name = vec![0; u16::from_le_bytes(length) as usize];
+        reader.read_exact(&mut name)?;
+        names.push(String::from_utf8(name).map_err(|_| invalid("File name is not valid UTF-8"))?);
+    }
+    Ok((records, names))
+}
+
+const DDS_HEADER_SIZE: usize = 128;
+const DX10_HEADER_SIZE: usize = 20;
+
+/// The parts of a DDS header a texture archive needs, with the format as a DXGI format
+pub(crate) struct DdsHeader {
+    pub(crate) size: usize,
+    height: u16,
+    width: u16,
+    mipmaps: u8,
+    format: u8,
+}
+
+impl DdsHeader {
+    pub(crate) fn parse(data: &[u8]) -> Result<Self, String> {
+        if data.len() < DDS_HEADER_SIZE || &data[0..4] != b"DDS " {
+            return Err("Not a DDS texture".to_string());
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        let (height, width, mipmaps) = (u32_at(12), u32_at(16), u32_at(28).max(1));
+        let (four_cc, bit_count, red_mask) = (&data[84..88], u32_at(88), u32_at(92));
+        let (size, format) = match four_cc {
+            b"DX10" if data.len() >= DDS_HEADER_SIZE + DX10_HEADER_SIZE => (DDS_HEADER_SIZE + DX10_HEADER_SIZE, u32_at(128)),
+            b"DXT1" => (DDS_HEADER_SIZE, 71),
+            b"DXT3" => (DDS_HEADER_SIZE, 74),
+            b"DXT5" => (DDS_HEADER_SIZE, 77),
+            b"ATI1" | b"BC4U" => (DDS_HEADER_SIZE, 80),
+            b"ATI2" | b"BC5U" => (DDS_HEADER_SIZE, 83),
+            [0, 0, 0, 0] if bit_count == 32 && red_mask == 0xFF => (DDS_HEADER_SIZE, 28),
+            [0, 0, 0, 0] if bit_count == 32 && red_mask == 0xFF_0000 => (DDS_HEADER_SIZE, 87),
+            _ => return Err("Unsupported DDS format".to_string()),
+        };
+        match (u16::try_from(height), u16::try_from(width), u8::try_from(mipmaps), u8::try_from(format)) {
+            (Ok(height), Ok(width), Ok(mipmaps), Ok(format)) => Ok(DdsHeader { size, height, width, mipmaps, format }),
+            _ => Err("Texture is too large for an archive".to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+
+    /// An empty folder for the files of one test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("autovideo-ba2-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Names and unpacked contents of the files of an archive, without texture headers
+    fn unpack(path: &Path, kind: ArchiveKind) -> Vec<(String, Vec<u8>)> {
+        let mut reader = BufReader::new(File::open(path).unwrap());
+        let (mut records, names) = read_index(&mut reader, kind).unwrap();
+        records.iter_mut().zip(names).map(|(record, name)| {
+            let mut data = vec![];
+            for chunk in record.chunks_mut() {
+                reader.seek(SeekFrom::Start(chunk.offset)).unwrap();
+                let mut stored = vec![0; chunk.stored_size() as usize];
+                reader.read_exact(&mut stored).unwrap();
+                if chunk.packed_size == 0 {
+                    data.extend(stored);
+                } else {
+                    ZlibDecoder::new(stored.as_slice()).read_to_end(&mut data).unwrap();
+                }
+                assert_eq!(data.len(), chunk.unpacked_size as usize);
+            }
+            (name, data)
+        }).collect()
+    }
+
+    #[test]
+    fn general_files_read_back_and_survive_repacking() {
+        let dir = test_dir("general");
+        let (sound, mesh) = (dir.join("Video.wav"), dir.join("Video.nif"));
+        fs::write(&sound, [1, 2, 3, 4, 5]).unwrap();
+        fs::write(&mesh, vec![7; 4096]).unwrap();
+        let archive = dir.join("Mod - Main.ba2");
+        pack(&archive, ArchiveKind::General, &[
+            ("Sound\\Videos\\Mod\\Video.wav".to_string(), sound.clone()),
+            ("Meshes\\Videos\\Mod\\Video.nif".to_string(), mesh.clone()),
+        ]).unwrap();
+        assert_eq!(unpack(&archive, ArchiveKind::General), [
+            ("Sound\\Videos\\Mod\\Video.wav".to_string(), vec![1, 2, 3, 4, 5]),
+            ("Meshes\\Videos\\Mod\\Video.nif".to_string(), vec![7; 4096]),
+        ]);
+
+        // Replacing a file keeps the others of the archive
+        fs::write(&mesh, [9; 10]).unwrap();
+        pack(&archive, ArchiveKind::General, &[("meshes\\videos\\mod\\video.nif".to_string(), mesh)]).unwrap();
+        assert_eq!(unpack(&archive, ArchiveKind::General), [
+            ("Sound\\Videos\\Mod\\Video.wav".to_string(), vec![1, 2, 3, 4, 5]),
+            ("meshes\\videos\\mod\\video.nif".to_string(), vec![9; 10]),
+        ]);
+        assert_eq!(file_names(&archive).unwrap(), ["Sound\\Videos\\Mod\\Video.wav", "meshes\\videos\\mod\\video.nif"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn textures_are_stored_without_their_header() {
+        let dir = test_dir("textures");
+        // A 4x4 DXT1 texture is a single block of 8 bytes
+        let mut dds = vec![0; DDS_HEADER_SIZE];
+        dds[0..4].copy_from_slice(b"DDS ");
+        dds[12..16].copy_from_slice(&4u32.to_le_bytes());
+        dds[16..20].copy_from_slice(&4u32.to_le_bytes());
+        dds[84..88].copy_from_slice(b"DXT1");
+        dds.extend([0xAB; 8]);
+        let texture = dir.join("Grid01.dds");
+        fs::write(&texture, &dds).unwrap();
+        let archive = dir.join("Mod - Textures.ba2");
+        pack(&archive, ArchiveKind::Textures, &[("Textures\\Videos\\Mod\\Video\\Grid01.dds".to_string(), texture)]).unwrap();
+
+        assert_eq!(unpack(&archive, ArchiveKind::Textures), [("Textures\\Videos\\Mod\\Video\\Grid01.dds".to_string(), vec![0xAB; 8])]);
+        let mut reader = BufReader::new(File::open(&archive).unwrap());
+        let (records, _) = read_index(&mut reader, ArchiveKind::Textures).unwrap();
+        let RecordData::Texture { height, width, mipmaps, format, .. } = records[0].data else { panic!("Not a texture record") };
+        assert_eq!((height, width, mipmaps, format), (4, 4, 1, 71));
+        assert!(read_index(&mut BufReader::new(File::open(&archive).unwrap()), ArchiveKind::General).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hashes_ignore_case_and_slashes() {
+        assert_eq!(hashes("Textures/Videos/Mod/Grid01.DDS"), hashes("textures\\videos\\mod\\grid01.dds"));
+        let (_, extension, _) = hashes("Sound\\Video.wav");
+        assert_eq!(&extension, b"wav\0");
+    }
+}
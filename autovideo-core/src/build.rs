@@ -0,0 +1,188 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use crate::ba2;
+use crate::convert::ConvertedVideo;
+use crate::error::{PathContext, Result};
+use crate::job::{JobOptions, PreparedVideo};
+use crate::utility::{fnv1a, FNV_OFFSET_BASIS};
+
+/// File in the output folder remembering what every video was converted from
+const BUILD_MANIFEST: &str = "autovideo-build.json";
+
+/// The videos converted into an output folder so far, by mod and video identifier.
+/// Videos whose input, settings and tool version are unchanged and whose files are still there are not converted again
+#[derive(Default, Serialize, Deserialize)]
+pub struct BuildManifest {
+    videos: BTreeMap<String, BuildEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BuildEntry {
+    #[serde(flatten)]
+    fingerprint: Fingerprint,
+    grid_amount: usize,
+    last_stop_time: f32,
+    audio_name: String,
+    outputs: Vec<BuildOutput>,
+}
+
+/// Everything a conversion depends on
+#[derive(Serialize, Deserialize)]
+pub struct Fingerprint {
+    version: String,
+    input_hash: String,
+    input_size: u64,
+    /// Nanoseconds since the Unix epoch. While the size and modification time of an input stay the same, its hash is not worked out again
+    #[serde(default)]
+    input_modified: Option<u64>,
+    settings: serde_json::Value,
+}
+
+/// The modification time only saves hashing, an input that was touched without changing is still the same
+impl PartialEq for Fingerprint {
+    fn eq(&self, other: &Self) -> bool {
+        (&self.version, &self.input_hash, self.input_size, &self.settings) == (&other.version, &other.input_hash, other.input_size, &other.settings)
+    }
+}
+
+/// A file written by a conversion, relative to the output folder. Packing archives moves it into one
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BuildOutput {
+    Loose(PathBuf),
+    Packed { archive: PathBuf, name: String },
+}
+
+impl Fingerprint {
+    /// Hashes the input of a video, along with the job settings that change its textures and sound.
+    /// The hash of an earlier fingerprint is reused when the input has the same size and modification time
+    fn of(video: &PreparedVideo, options: &JobOptions, earlier: Option<&Fingerprint>) -> Result<Fingerprint> {
+        let metadata = fs::metadata(&video.path).at(&video.path)?;
+        let input_size = metadata.len();
+        let input_modified = metadata.modified().ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_nanos() as u64);
+        let input_hash = match earlier.filter(|earlier| input_modified.is_some() && (earlier.input_size, earlier.input_modified) == (input_size, input_modified)) {
+            Some(earlier) => earlier.input_hash.clone(),
+            None => hash_file(&video.path)?
+        };
+        let output = &options.output;
+        let texture_root = output.texture_root(options.size);
+        let settings = json!({
+            "framerate": video.framerate,
+            "trim": video.trim,
+            "aspect": video.aspect,
+            "size": options.size,
+            "screen_sizes": options.screen_sizes,
+            "layout": options.layout,
+            "texture": options.texture,
+            "audio_format": options.audio_format,
+            "texture_root": texture_root.strip_prefix(&output.output_dir).unwrap_or(&texture_root),
+        });
+        Ok(Fingerprint { version: env!("CARGO_PKG_VERSION").to_string(), input_hash, input_size, input_modified, settings })
+    }
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut file = BufReader::new(File::open(path).at(path)?);
+    let mut hash = FNV_OFFSET_BASIS;
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let read = file.read(&mut buffer).at(path)?;
+        if read == 0 {
+            return Ok(format!("{hash:016x}"));
+        }
+        hash = fnv1a(hash, &buffer[..read]);
+    }
+}
+
+impl BuildManifest {
+    /// The manifest of an output folder. A missing or unreadable one only means that every video is converted
+    pub fn load(output_dir: &Path) -> BuildManifest {
+        fs::read_to_string(output_dir.join(BUILD_MANIFEST)).ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn exists(output_dir: &Path) -> bool {
+        output_dir.join(BUILD_MANIFEST).is_file()
+    }
+
+    pub fn save(&self, output_dir: &Path) -> Result<()> {
+        let path = output_dir.join(BUILD_MANIFEST);
+        let contents = serde_json::to_string_pretty(self).expect("Build manifests serialize");
+        fs::write(&path, contents).at(&path)
+    }
+
+    /// Fingerprint of a video, only reading its whole input when it changed size or modification time since it was last converted
+    pub fn fingerprint(&self, mod_identifier: &str, video: &PreparedVideo, options: &JobOptions) -> Result<Fingerprint> {
+        Fingerprint::of(video, options, self.videos.get(&key(mod_identifier, video)).map(|entry| &entry.fingerprint))
+    }
+
+    /// Result of an earlier conversion of the video, if it had the same fingerprint and all of its files are still there
+    pub fn unchanged(&mut self, mod_identifier: &str, video: &PreparedVideo, fingerprint: &Fingerprint, output_dir: &Path) -> Option<ConvertedVideo> {
+        let entry = self.videos.get_mut(&key(mod_identifier, video))?;
+        if entry.fingerprint != *fingerprint {
+            return None;
+        }
+        let mut archives: HashMap<&PathBuf, Vec<String>> = HashMap::new();
+        for output in &entry.outputs {
+            let present = match output {
+                BuildOutput::Loose(path) => output_dir.join(path).is_file(),
+                BuildOutput::Packed { archive, name } => archives.entry(archive)
+                    .or_insert_with(|| ba2::file_names(&output_dir.join(archive)).unwrap_or_default())
+                    .iter().any(|packed| packed.eq_ignore_ascii_case(name))
+            };
+            if !present {
+                return None;
+            }
+        }
+        entry.fingerprint.input_modified = fingerprint.input_modified;
+        Some(ConvertedVideo {
+            grid_amount: entry.grid_amount,
+            last_stop_time: entry.last_stop_time,
+            audio_name: entry.audio_name.clone(),
+            files: vec![],
+        })
+    }
+
+    /// Remembers a video that was just converted
+    pub fn insert(&mut self, mod_identifier: &str, video: &PreparedVideo, fingerprint: Fingerprint, converted: &ConvertedVideo, output_dir: &Path) {
+        let outputs = converted.files.iter()
+            .map(|file| BuildOutput::Loose(file.strip_prefix(output_dir).unwrap_or(file).to_path_buf()))
+            .collect();
+        self.videos.insert(key(mod_identifier, video), BuildEntry {
+            fingerprint,
+            grid_amount: converted.grid_amount,
+            last_stop_time: converted.last_stop_time,
+            audio_name: converted.audio_name.clone(),
+            outputs,
+        });
+    }
+
+    /// Drops what is known about a video that was converted again without a fingerprint, as its files no longer match it
+    pub fn forget(&mut self, mod_identifier: &str, video: &PreparedVideo) {
+        self.videos.remove(&key(mod_identifier, video));
+    }
+
+    /// Notes loose files that were moved into an archive, given as the file, the archive and its name in there
+    pub fn packed(&mut self, moved: &[(PathBuf, PathBuf, String)], output_dir: &Path) {
+        let relative = |path: &Path| path.strip_prefix(output_dir).unwrap_or(path).to_path_buf();
+        let moved: HashMap<PathBuf, (&PathBuf, &String)> = moved.iter().map(|(file, archive, name)| (relative(file), (archive, name))).collect();
+        for output in self.videos.values_mut().flat_map(|entry| &mut entry.outputs) {
+            let BuildOutput::Loose(path) = output else { continue };
+            if let Some((archive, name)) = moved.get(path) {
+                *output = BuildOutput::Packed { archive: relative(archive), name: name.to_string() };
+            }
+        }
+    }
+}
+
+fn key(mod_identifier: &str, video: &PreparedVideo) -> String {
+    format!("{mod_identifier}/{}", video.identifier)
+}
@@ -0,0 +1,26 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::error::{AutovideoError, Result};
+
+/// Stops a running conversion from another thread. Clones share their state, cancelling one cancels all of them
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Fails with [`AutovideoError::Cancelled`] once cancelled, for checks between steps
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.is_cancelled() { Err(AutovideoError::Cancelled) } else { Ok(()) }
+    }
+}
@@ -1,178 +1,360 @@
 use std::fs;
-use std::fs::File;
-use std::io::Write;
-use std::path::{Path, PathBuf};
-use std::process::Command;
-use image::{GenericImageView};
+use std::panic;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+#[cfg(not(feature = "native-decode"))]
+use std::io::{self, BufRead, BufReader};
+#[cfg(not(feature = "native-decode"))]
+use std::{path::Path, process, process::{Command, Stdio}, time::Duration};
+use image::imageops::{self, FilterType};
+#[cfg(not(feature = "native-decode"))]
+use image::RgbaImage;
+#[cfg(not(feature = "native-decode"))]
 use rayon::prelude::*;
-use crate::Mode;
-use crate::utility::{save_as_dds, time_number_to_string, user_input};
+use crate::aspect::FrameFitter;
+use crate::audio;
+use crate::cancel::CancellationToken;
+use crate::error::{AutovideoError, PathContext, Result};
+#[cfg(not(feature = "native-decode"))]
+use crate::filter::FilterGraph;
+use crate::grid::{grid_file_name, GridEncoder, GridGeometry, GridWriter};
+#[cfg(not(feature = "native-decode"))]
+use crate::probe::{probe, ProbeInfo};
+use crate::progress::{ProgressEvent, Stage, VideoProgress};
+use crate::job::{JobOptions, PreparedVideo};
+use crate::{sound_folder, texture_folder, Mode};
+use crate::utility::{time_number_to_string, user_input};
 
-pub fn convert_video<F: FnMut()>(
-    input: PathBuf,
+/// Only one conversion asks a question at a time
+static PROMPT: Mutex<()> = Mutex::new(());
+
+/// How often a running ffmpeg is checked for having finished or being cancelled
+#[cfg(not(feature = "native-decode"))]
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Stage the frames are put into grids in, the ffmpeg executable extracts all of them before they are read
+#[cfg(feature = "native-decode")]
+const FRAME_STAGE: Stage = Stage::Extracting;
+#[cfg(not(feature = "native-decode"))]
+const FRAME_STAGE: Stage = Stage::Reading;
+
+/// What the meshes and esps need to know about a converted video
+pub struct ConvertedVideo {
+    pub grid_amount: usize,
+    /// Time the last grid stops at, it is usually not full
+    pub last_stop_time: f32,
+    pub audio_name: String,
+    /// Grid textures and sound written by the conversion
+    pub files: Vec<PathBuf>,
+}
+
+/// Converts the videos on up to `parallel_videos` threads, so ffmpeg decodes one video while the grids of another are encoded.
+/// The grids of all videos share one encoder. Results are in the order of the videos.
+/// After an error no more videos are started, and the first error in that order is returned
+pub fn convert_videos<F: FnMut(ProgressEvent) + Send>(
+    videos: &[(usize, &PreparedVideo)],
     mod_identifier: &str,
-    video_identifier: &str,
-    frame_size: u32,
-    auto_scale: bool,
+    options: &JobOptions,
     mode: &Mode,
-    framerate: u32,
-    mut checkpoint_reached: F,
-    has_nvenc: bool,
-    high_quality: bool
-) -> Result<(u8, f32, String), String> {
-    let audio_path = format!("output/Sound/Videos/{mod_identifier}");
-    fs::create_dir_all(&audio_path).unwrap();
-    let wav_name = format!("{video_identifier}.wav");
-    let wav_path = format!("{audio_path}/{wav_name}");
-    let input_str = input.to_str().unwrap();
-
-    if !Path::new("./autovideo cache/frames").exists() {
-        fs::create_dir_all("./autovideo cache/frames").unwrap();
-    }
-    let padded_video_path = "./autovideo cache/Video.mp4";
-    
-    if auto_scale {
-        let mut args: Vec<&str> = ["-i", input_str, "-c:a", "copy", "-vf", "pad=max(iw\\,ih*4/3):max(ih\\,iw*3/4):(ow-iw)/2:(oh-ih)/2", "-y"].into();
-        if has_nvenc {
-            args.extend(["-c:v", "hevc_nvenc", "-cq:v", "18"]);
-        } else {
-            args.extend(["-crf", "18"]);
-        }
-        args.push(padded_video_path);
-        match Command::new("ffmpeg").args(args).status() {
-            Ok(ffmpeg_status) => {
-                if !ffmpeg_status.success() {
-                    return Err("Failed to pad video".to_string());
+    cancel: &CancellationToken,
+    on_progress: F
+) -> Result<Vec<ConvertedVideo>> {
+    let on_progress = Mutex::new(on_progress);
+    let send = |event| (on_progress.lock().unwrap())(event);
+    let next_video = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results: Vec<Mutex<Option<Result<ConvertedVideo>>>> = videos.iter().map(|_| Mutex::new(None)).collect();
+    let encoder = GridEncoder::new();
+    thread::scope(|scope| {
+        encoder.start(scope);
+        let workers: Vec<_> = (0..options.parallel_videos.clamp(1, videos.len().max(1))).map(|_| scope.spawn(|| {
+            while !failed.load(Ordering::Relaxed) {
+                let index = next_video.fetch_add(1, Ordering::Relaxed);
+                let Some(&(video_index, video)) = videos.get(index) else { break };
+                let progress = VideoProgress::new(&send, video_index, video.geometry.frames_per_grid(), video.grid_sets.len());
+                let result = convert_video(video, mod_identifier, options, mode, cancel, &encoder, &progress);
+                if result.is_err() {
+                    failed.store(true, Ordering::Relaxed);
                 }
+                *results[index].lock().unwrap() = Some(result);
+            }
+        })).collect();
+        let joined: Vec<_> = workers.into_iter().map(|worker| worker.join()).collect();
+        // The encoder threads only end once it is closed, also when a conversion panicked
+        encoder.close();
+        for result in joined {
+            if let Err(panic) = result {
+                panic::resume_unwind(panic);
             }
-            Err(e) => return Err(format!("{}: ffmpeg is not installed!", e))
         }
-        checkpoint_reached();
+    });
+    // Videos are started in order, so any video without a result comes after the one that failed
+    results.into_iter().map_while(|result| result.into_inner().unwrap()).collect()
+}
+
+/// Converts a single video. Nothing of a video that failed or was cancelled is kept, it is converted from scratch next time
+fn convert_video(
+    video: &PreparedVideo,
+    mod_identifier: &str,
+    options: &JobOptions,
+    mode: &Mode,
+    cancel: &CancellationToken,
+    encoder: &GridEncoder,
+    progress: &VideoProgress
+) -> Result<ConvertedVideo> {
+    let converted = convert_video_into(video, mod_identifier, options, mode, cancel, encoder, progress);
+    if converted.is_err() {
+        let (video_path, wav_path) = output_paths(video, mod_identifier, options);
+        if video_path.exists() {
+            fs::remove_dir_all(&video_path).at(&video_path)?;
+        }
+        if wav_path.exists() {
+            fs::remove_file(&wav_path).at(&wav_path)?;
+        }
     }
+    converted
+}
 
-    let video_path = if auto_scale { padded_video_path } else { input_str };
+/// Folder of the grid textures and path of the sound of a video
+fn output_paths(video: &PreparedVideo, mod_identifier: &str, options: &JobOptions) -> (PathBuf, PathBuf) {
+    let video_path = options.output.texture_root(options.size).join(texture_folder(mod_identifier)).join(&video.identifier);
+    let wav_path = options.output.output_dir.join(sound_folder(mod_identifier)).join(format!("{}.wav", video.identifier));
+    (video_path, wav_path)
+}
 
-    let has_sound = match Command::new("ffprobe").args(["-i", video_path, "-show_streams", "-select_streams", "a", "-loglevel", "error"]).output() {
-        Ok(probe) => {
-            if !probe.status.success() {
-                return Err(format!("Failed to probe video for audio track: {input_str}"))
+fn convert_video_into(
+    video: &PreparedVideo,
+    mod_identifier: &str,
+    options: &JobOptions,
+    mode: &Mode,
+    cancel: &CancellationToken,
+    encoder: &GridEncoder,
+    progress: &VideoProgress
+) -> Result<ConvertedVideo> {
+    let PreparedVideo { identifier: video_identifier, path: input, framerate, geometry, .. } = video;
+    let (geometry, framerate) = (*geometry, *framerate);
+    let (video_path, wav_path) = output_paths(video, mod_identifier, options);
+    let audio_path = options.output.output_dir.join(sound_folder(mod_identifier));
+    fs::create_dir_all(&audio_path).at(&audio_path)?;
+    let wav_name = format!("{video_identifier}.wav");
+    let video_title = input.file_stem().unwrap_or_default().to_string_lossy();
+
+    let mut grid_sets = vec![];
+    for (index, set) in video.grid_sets.iter().enumerate() {
+        let grids_path = set.folder.as_ref().map_or(video_path.clone(), |folder| video_path.join(folder));
+        fs::create_dir_all(&grids_path).at(&grids_path)?;
+        // All sets get their grids at the same time, asking once is enough
+        let on_new_grid: Box<dyn FnMut(usize) -> Result<()>> = if index == 0 {
+            Box::new(|full_grids| confirm_length(&video_title, framerate, geometry, full_grids, mode))
+        } else {
+            Box::new(|_| Ok(()))
+        };
+        let grids = GridWriter::new(encoder, set.geometry, grids_path.clone(), options.texture, on_new_grid);
+        grid_sets.push((set, grids_path, grids));
+    }
+    // Frames may come in only scaled, they are padded or cropped to the frame size in order so smart crops can pan smoothly.
+    // Smaller grid sets get a scaled down copy of the fitted frame
+    let mut fitter = FrameFitter::new(video.aspect, &geometry);
+    let mut frames_read = 0;
+    let on_frame = |frame| {
+        cancel.check()?;
+        let frame = fitter.fit(frame);
+        for (set, _, grids) in &mut grid_sets {
+            let GridGeometry { frame_width, frame_height, .. } = set.geometry;
+            if frame.dimensions() == (frame_width, frame_height) {
+                grids.push(&frame)?;
             } else {
-                !String::from_utf8(probe.stdout).unwrap().trim().is_empty()
+                grids.push(&imageops::resize(&frame, frame_width, frame_height, FilterType::Triangle))?;
             }
         }
-        Err(e) => return Err(format!("{e}: ffprobe not found!"))
+        frames_read += 1;
+        progress.update(FRAME_STAGE, Some(frames_read), Some(grid_sets.iter().map(|(_, _, grids)| grids.grids_encoded()).sum()));
+        Ok(())
     };
 
-    let mut args: Vec<String> = [
-        "-i", video_path,
-        "-filter:v", &format!("scale={frame_size}:{frame_size}"),
-        "-r", &format!("{framerate}"),
-        "-f", "image2",
-        "-c:v", "png",
-        "-b:v", "2M",
-        "-maxrate", "2M",
-        "-bufsize", "1M",
-        "-async", "44000",
-        "-y",
-        "autovideo cache/frames/%04d.png"
-    ].iter().map(|s| s.to_string()).collect();
-    if has_sound {
-        args.extend(["-ac", "1", &wav_path].map(|s| s.to_string()));
+    #[cfg(feature = "native-decode")]
+    let decoded = crate::decode::decode_video(video, &wav_path, cancel, progress, on_frame);
+    #[cfg(not(feature = "native-decode"))]
+    let decoded = extract_frames(video, mod_identifier, options, &wav_path, cancel, progress, on_frame);
+    decoded?;
+
+    if wav_path.exists() {
+        audio::encode_wav(&wav_path, options.audio_format)?;
     }
-    match Command::new("ffmpeg").args(args).status() {
-        Ok(ffmpeg_status) => {
-            if !ffmpeg_status.success() {
-                return Err(format!("Failed to convert video: {input_str}"));
-            }
+
+    println!("Finishing grids ...");
+    progress.set_frames_total(frames_read);
+    progress.report(Stage::EncodingGrids, Some(frames_read), Some(grid_sets.iter().map(|(_, _, grids)| grids.grids_encoded()).sum()));
+    let mut grid_amounts = vec![];
+    let mut files: Vec<PathBuf> = wav_path.exists().then(|| wav_path.clone()).into_iter().collect();
+    for (set, grids_path, grids) in grid_sets {
+        let (grid_amount, last_grid_frame_amount) = grids.finish()?;
+        // Grids of screens that can't show a video this long are of no use
+        if set.screens.iter().all(|screen| screen.max_grids().is_some_and(|max_grids| grid_amount > max_grids)) {
+            fs::remove_dir_all(&grids_path).at(&grids_path)?;
+        } else {
+            files.extend((1..=grid_amount).map(|grid| grids_path.join(grid_file_name(grid))));
         }
-        Err(e) => return Err(format!("{}: ffmpeg is not installed!", e))
-    }
-    checkpoint_reached();
-    let xwm_name = format!("{video_identifier}.xwm");
-    let xwm_path = format!("{audio_path}/{xwm_name}");
-    let xwma_encoder_path = Path::new("./autovideo cache/xWMAEncode.exe");
-    if !xwma_encoder_path.exists() {
-        let encoder_bytes = include_bytes!("./assets/xWMAEncode.exe");
-        let mut encoder_file = File::create(xwma_encoder_path).unwrap();
-        encoder_file.write_all(encoder_bytes).unwrap();
+        grid_amounts.push((grid_amount, last_grid_frame_amount));
     }
-    Command::new("./autovideo cache/xWMAEncode").args([&wav_path, &xwm_path]).status().unwrap();
-    let xwm_exists = Path::new(&xwm_path).exists();
-    if xwm_exists {
-        fs::remove_file(&wav_path).unwrap();
-    }
-    if auto_scale {
-        fs::remove_file(padded_video_path).unwrap_or_else(|e| println!("{}", e));
+    let (grid_amount, last_grid_frame_amount) = grid_amounts[0];
+    if grid_amount == 0 {
+        return Err(AutovideoError::ConversionFailed { input: input.clone(), message: "ffmpeg did not produce any frames".to_string() });
     }
 
-    println!("\nReading frames ...");
-    let frames: Vec<_> = fs::read_dir("./autovideo cache/frames").unwrap().flatten().collect();
-    let frames: Vec<_> = frames.par_iter().map(|f| image::open(f.path()).unwrap()).chunks(256).collect();
-    fs::remove_dir_all("./autovideo cache/frames").unwrap();
+    let grids_encoded = grid_amounts.iter().map(|(grid_amount, _)| grid_amount).sum();
+    progress.report(Stage::Done, Some(frames_read), Some(grids_encoded));
     
-    let grid_amount = frames.len();
-    if grid_amount > 24 {
-        let mut max_time = 614.4;
-        if framerate != 10 {
-            max_time = max_time / framerate as f64 * 10f64;
-        }
-        if matches!(mode, Mode::UiMode) {
-            return Err(format!(
-                "Video {} is longer than {} (24 grids). Reduce FPS or use a shorter video.",
-                input.file_stem().unwrap().to_str().unwrap(),
-                time_number_to_string(max_time)
-            ));
-        } else {
-            let message = format!(
-                "\nIt seems that \"{}\" is longer than {} (24 grids), the extra length will be cut off and no DriveIn version will be made.\
-                \nDo you want to continue? (y/N) ",
-                input.file_stem().unwrap().to_str().unwrap(),
-                time_number_to_string(max_time)
-            );
-            if matches!(mode, Mode::YES) {
-                println!("{message}Y");
-            } else if user_input(&message).to_lowercase() != "y" {
-                return Err("Input video too long".to_string());
-            }
-        }
-    } else if grid_amount > 8 && !matches!(mode, Mode::UiMode) {
-        let mut max_time = 204.8;
-        if framerate != 10 {
-            max_time = max_time / framerate as f64 * 10f64;
-        }
+    Ok(ConvertedVideo { grid_amount, last_stop_time: last_grid_frame_amount as f32 / 10f32, audio_name: wav_name, files })
+}
+
+/// Asks whether to continue once a video turns out longer than 8 grids, the most the DriveIn mesh can show
+fn confirm_length(video_title: &str, framerate: u32, geometry: GridGeometry, full_grids: usize, mode: &Mode) -> Result<()> {
+    if full_grids == 8 && !matches!(mode, Mode::UiMode) {
+        let max_time = (8 * geometry.frames_per_grid()) as f64 / framerate as f64;
         let message = format!(
             "\nIt seems that \"{}\" is longer than {} (8 grids), no DriveIn version will be made.\
             \nDo you want to continue? (y/N) ",
-            input.file_stem().unwrap().to_str().unwrap(),
+            video_title,
             time_number_to_string(max_time)
         );
+        let _prompt = PROMPT.lock().unwrap_or_else(|e| e.into_inner());
         if matches!(mode, Mode::YES) {
             println!("{message}Y");
         } else if user_input(&message).to_lowercase() != "y" {
-            return Err("Input video too long for DriveIn".to_string());
+            return Err(AutovideoError::VideoTooLong { video: video_title.to_string(), max_length: time_number_to_string(max_time), grids: 8 });
         }
     }
-    let last_chunk_frame_amount = frames.last().unwrap().len();
-
-    let grids_path_string = format!("output/textures/Videos/{mod_identifier}/{video_identifier}");
-    let grids_path = Path::new(&grids_path_string);
-    fs::create_dir_all(grids_path).unwrap();
-
-    println!("Converting frames to grids ...");
-    let dimension = frame_size * 16;
-    frames.into_par_iter().enumerate().for_each(|(grid_index, grid)| {
-        let mut output_grid = image::RgbaImage::from_pixel(dimension, dimension, [0, 0, 0, 255].into());
-        for (index, frame) in grid.iter().enumerate() {
-            let x_offset = (index as u32 % 16) * frame_size;
-            let y_offset = (index as u32 / 16) * frame_size;
-            for (x, y, pixel) in frame.pixels() {
-                output_grid.put_pixel(x + x_offset, y + y_offset, pixel);
+    Ok(())
+}
+
+/// Extracts the frames and audio of a video in a single run of the ffmpeg executable, going through png files in the cache
+#[cfg(not(feature = "native-decode"))]
+fn extract_frames<F: FnMut(RgbaImage) -> Result<()>>(
+    video: &PreparedVideo,
+    mod_identifier: &str,
+    options: &JobOptions,
+    wav_path: &Path,
+    cancel: &CancellationToken,
+    progress: &VideoProgress,
+    on_frame: F
+) -> Result<()> {
+    // Scratch folder of this video only, so jobs sharing a cache folder don't clobber each other
+    let scratch_path = options.output.cache_dir.join(format!("{}-{mod_identifier}-{}", process::id(), video.identifier));
+    let extracted = extract_frames_into(video, &scratch_path, wav_path, cancel, progress, on_frame);
+    if extracted.is_ok() || matches!(extracted, Err(AutovideoError::Cancelled)) {
+        fs::remove_dir_all(&scratch_path).at(&scratch_path)?;
+    }
+    extracted
+}
+
+#[cfg(not(feature = "native-decode"))]
+fn extract_frames_into<F: FnMut(RgbaImage) -> Result<()>>(
+    video: &PreparedVideo,
+    scratch_path: &Path,
+    wav_path: &Path,
+    cancel: &CancellationToken,
+    progress: &VideoProgress,
+    mut on_frame: F
+) -> Result<()> {
+    let PreparedVideo { path: input, framerate, trim, aspect, geometry, .. } = video;
+    let GridGeometry { frame_width, frame_height, .. } = geometry;
+
+    let frames_path = scratch_path.join("frames");
+    fs::create_dir_all(&frames_path).at(&frames_path)?;
+
+    let ProbeInfo { duration, has_sound, .. } = probe(input)?;
+    if let Some(duration) = duration {
+        progress.set_frames_total(video.frame_amount(duration));
+    }
+
+    let mut graph = FilterGraph::new();
+    if let Some(trim) = trim {
+        graph = graph.trim(trim);
+    }
+    let graph = graph.fps(*framerate).aspect(*aspect, *frame_width, *frame_height);
+    let mut command = Command::new("ffmpeg");
+    command.arg("-i").arg(input).args([
+        "-filter_complex", &graph.build(has_sound),
+        "-map", "[video]",
+        "-f", "image2",
+        "-c:v", "png",
+        "-progress", "pipe:1",
+        "-y",
+    ]).arg(frames_path.join("%04d.png"));
+    if has_sound {
+        command.args(["-map", "[audio]", "-ac", "1"]).arg(wav_path);
+    }
+    let mut ffmpeg = command.stdout(Stdio::piped()).spawn()
+        .map_err(|e| AutovideoError::FfmpegMissing { program: "ffmpeg".to_string(), message: e.to_string() })?;
+    let ffmpeg_progress = ffmpeg.stdout.take().expect("ffmpeg output is piped");
+    let frames_extracted = AtomicUsize::new(0);
+    let ffmpeg_status = thread::scope(|scope| {
+        // `-progress` writes blocks of key=value lines, ending when ffmpeg exits or is killed
+        scope.spawn(|| {
+            for line in BufReader::new(ffmpeg_progress).lines().map_while(io::Result::ok) {
+                if let Some(Ok(frame)) = line.strip_prefix("frame=").map(|frame| frame.trim().parse()) {
+                    frames_extracted.store(frame, Ordering::Relaxed);
+                }
+            }
+        });
+        loop {
+            if cancel.is_cancelled() {
+                // Already exited when this fails, waiting reaps it either way
+                let _ = ffmpeg.kill();
+                let _ = ffmpeg.wait();
+                return Err(AutovideoError::Cancelled);
+            }
+            progress.update(Stage::Extracting, Some(frames_extracted.load(Ordering::Relaxed)), None);
+            match ffmpeg.try_wait() {
+                Ok(Some(status)) => return Ok(status),
+                Ok(None) => thread::sleep(CANCEL_POLL_INTERVAL),
+                Err(e) => return Err(AutovideoError::ConversionFailed { input: input.clone(), message: e.to_string() }),
             }
         }
-        save_as_dds(&output_grid, format!("{}/Grid{:0>2}.dds", grids_path_string, grid_index + 1), high_quality);
-    });
+    })?;
+    if !ffmpeg_status.success() {
+        return Err(AutovideoError::ConversionFailed { input: input.clone(), message: "Failed to convert video".to_string() });
+    }
 
-    checkpoint_reached();
-    
-    Ok((grid_amount as u8, last_chunk_frame_amount as f32 / 10f32, if xwm_exists { xwm_name } else { wav_name }))
-}
\ No newline at end of file
+    println!("\nReading frames ...");
+    let frame_paths = sorted_frames(fs::read_dir(&frames_path).at(&frames_path)?.flatten().map(|f| f.path()).collect());
+    progress.set_frames_total(frame_paths.len());
+    // Only one grid worth of frames is loaded at a time
+    for chunk in frame_paths.chunks(geometry.frames_per_grid()) {
+        cancel.check()?;
+        let frames = chunk.par_iter()
+            .map(|path| image::open(path).map(|frame| frame.into_rgba8()).map_err(|e| AutovideoError::Image { path: path.clone(), message: e.to_string() }))
+            .collect::<Result<Vec<_>>>()?;
+        for frame in frames {
+            on_frame(frame)?;
+        }
+    }
+    Ok(())
+}
+
+/// Orders extracted frames by their number. Names only sort correctly up to 9999, after that ffmpeg makes them longer
+#[cfg(not(feature = "native-decode"))]
+fn sorted_frames(frame_paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut numbered: Vec<(u64, PathBuf)> = frame_paths.into_iter()
+        .filter_map(|path| Some((path.file_stem()?.to_str()?.parse().ok()?, path)))
+        .collect();
+    numbered.sort();
+    numbered.into_iter().map(|(_, path)| path).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(not(feature = "native-decode"))]
+    fn frames_past_9999_stay_in_order() {
+        let names = ["10000.png", "1000.png", "9999.png", "1001.png", "0001.png", "10001.png"];
+        let sorted = sorted_frames(names.iter().map(|name| Path::new("frames").join(name)).collect());
+        let sorted: Vec<_> = sorted.iter().map(|path| path.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(sorted, ["0001.png", "1000.png", "1001.png", "9999.png", "10000.png", "10001.png"]);
+    }
+}
@@ -0,0 +1,291 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use ffmpeg_next as ffmpeg;
+use ffmpeg::codec::context::Context;
+use ffmpeg::format::{sample, stream::Stream, Pixel, Sample};
+use ffmpeg::media::Type;
+use ffmpeg::software::scaling;
+use ffmpeg::util::frame::{audio::Audio, video::Video};
+use ffmpeg::{ChannelLayout, Rational};
+use image::RgbaImage;
+use crate::aspect::AspectMode;
+use crate::cancel::CancellationToken;
+use crate::error::{AutovideoError, PathContext, Result};
+use crate::job::{PreparedVideo, Trim};
+use crate::progress::VideoProgress;
+
+/// Decodes a video with the ffmpeg libraries, passing its frames at the video framerate, scaled for the frames of its grids, to `on_frame`.
+/// The audio, if any, is written as a mono wav to `wav_path`. Times count from the start of the earliest stream, like the ffmpeg executable does
+pub fn decode_video<F: FnMut(RgbaImage) -> Result<()>>(
+    video: &PreparedVideo,
+    wav_path: &Path,
+    cancel: &CancellationToken,
+    progress: &VideoProgress,
+    mut on_frame: F
+) -> Result<()> {
+    let failed = |e: ffmpeg::Error| AutovideoError::ConversionFailed { input: video.path.clone(), message: e.to_string() };
+    ffmpeg::init().map_err(failed)?;
+    let mut input = ffmpeg::format::input(&video.path).map_err(failed)?;
+    if input.duration() > 0 {
+        progress.set_frames_total(video.frame_amount(input.duration() as f64 / ffmpeg::ffi::AV_TIME_BASE as f64));
+    }
+    let (start, end) = match video.trim {
+        Some(Trim { start, end }) => (start.unwrap_or(0.0), end.unwrap_or(f64::INFINITY)),
+        None => (0.0, f64::INFINITY)
+    };
+
+    // Streams often don't start at zero, their times are shifted by the same amount to keep them in sync
+    let offset = [Type::Video, Type::Audio].into_iter()
+        .filter_map(|kind| input.streams().best(kind).and_then(|stream| start_time(&stream)))
+        .reduce(f64::min)
+        .unwrap_or(0.0);
+
+    let (video_index, mut video_decoder, mut sampler) = {
+        let stream = input.streams().best(Type::Video).ok_or(ffmpeg::Error::StreamNotFound).map_err(failed)?;
+        let decoder = Context::from_parameters(stream.parameters()).and_then(|context| context.decoder().video()).map_err(failed)?;
+        let rate = stream.avg_frame_rate();
+        let frame_duration = if rate.numerator() > 0 { f64::from(rate.invert()) } else { 0.0 };
+        (stream.index(), decoder, FrameSampler::new(video, start, end, stream.time_base(), offset, frame_duration))
+    };
+    let audio = match input.streams().best(Type::Audio) {
+        Some(stream) => {
+            let decoder = Context::from_parameters(stream.parameters()).and_then(|context| context.decoder().audio()).map_err(failed)?;
+            Some((stream.index(), decoder, SampleCollector::new(start, end, stream.time_base(), offset)))
+        }
+        None => None
+    };
+    let (audio_index, mut audio) = match audio {
+        Some((index, decoder, collector)) => (Some(index), Some((decoder, collector))),
+        None => (None, None)
+    };
+
+    if start > 0.0 {
+        let position = ((start + offset) * ffmpeg::ffi::AV_TIME_BASE as f64) as i64;
+        input.seek(position, ..position).map_err(failed)?;
+    }
+
+    let mut decoded_video = Video::empty();
+    let mut decoded_audio = Audio::empty();
+    for (stream, packet) in input.packets() {
+        cancel.check()?;
+        if stream.index() == video_index && !sampler.done {
+            video_decoder.send_packet(&packet).map_err(failed)?;
+            while video_decoder.receive_frame(&mut decoded_video).is_ok() {
+                sampler.push(&decoded_video).map_err(failed)?;
+                sampler.drain_into(&mut on_frame)?;
+            }
+        } else if Some(stream.index()) == audio_index {
+            if let Some((decoder, samples)) = audio.as_mut().filter(|(_, samples)| !samples.done) {
+                decoder.send_packet(&packet).map_err(failed)?;
+                while decoder.receive_frame(&mut decoded_audio).is_ok() {
+                    samples.push(&decoded_audio).map_err(failed)?;
+                }
+            }
+        }
+        if sampler.done && audio.as_ref().is_none_or(|(_, samples)| samples.done) {
+            break;
+        }
+    }
+
+    if !sampler.done {
+        video_decoder.send_eof().map_err(failed)?;
+        while video_decoder.receive_frame(&mut decoded_video).is_ok() {
+            sampler.push(&decoded_video).map_err(failed)?;
+        }
+        sampler.finish().map_err(failed)?;
+        sampler.drain_into(&mut on_frame)?;
+    }
+    if let Some((mut decoder, mut samples)) = audio {
+        decoder.send_eof().map_err(failed)?;
+        while decoder.receive_frame(&mut decoded_audio).is_ok() {
+            samples.push(&decoded_audio).map_err(failed)?;
+        }
+        write_wav(wav_path, samples.rate, &samples.samples).at(wav_path)
+    } else {
+        Ok(())
+    }
+}
+
+/// Time of the first packet of a stream in seconds, if the container knows it
+fn start_time(stream: &Stream) -> Option<f64> {
+    (stream.start_time() != ffmpeg::ffi::AV_NOPTS_VALUE).then(|| stream.start_time() as f64 * f64::from(stream.time_base()))
+}
+
+/// Picks the frames shown at every tick of the output framerate, like the `-r` option of ffmpeg
+struct FrameSampler {
+    frame_width: u32,
+    frame_height: u32,
+    aspect: AspectMode,
+    framerate: u32,
+    start: f64,
+    end: f64,
+    time_base: Rational,
+    /// Start time of the earliest stream, subtracted from the frame times
+    offset: f64,
+    frame_duration: f64,
+    scaler: Option<scaling::Context>,
+    /// The last decoded frame with its time, scaled once it is used
+    last: Option<(f64, Video, Option<RgbaImage>)>,
+    /// Frames that were picked but not yet passed on
+    frames: Vec<RgbaImage>,
+    emitted: usize,
+    done: bool,
+}
+
+impl FrameSampler {
+    fn new(video: &PreparedVideo, start: f64, end: f64, time_base: Rational, offset: f64, frame_duration: f64) -> Self {
+        FrameSampler {
+            frame_width: video.geometry.frame_width,
+            frame_height: video.geometry.frame_height,
+            aspect: video.aspect,
+            framerate: video.framerate,
+            start,
+            end,
+            time_base,
+            offset,
+            frame_duration,
+            scaler: None,
+            last: None,
+            frames: Vec::new(),
+            emitted: 0,
+            done: false,
+        }
+    }
+
+    fn push(&mut self, frame: &Video) -> Result<(), ffmpeg::Error> {
+        let time = match frame.timestamp().or(frame.pts()) {
+            Some(timestamp) => timestamp as f64 * f64::from(self.time_base) - self.offset,
+            None => self.last.as_ref().map_or(0.0, |(time, _, _)| time + self.frame_duration)
+        };
+        self.emit_until(time.min(self.end))?;
+        if time >= self.end {
+            self.done = true;
+        } else {
+            self.last = Some((time, frame.clone(), None));
+        }
+        Ok(())
+    }
+
+    /// Shows the last frame for its whole duration
+    fn finish(&mut self) -> Result<(), ffmpeg::Error> {
+        if let Some((time, _, _)) = self.last {
+            self.emit_until((time + self.frame_duration).min(self.end))?;
+        }
+        self.done = true;
+        Ok(())
+    }
+
+    /// Passes the picked frames on
+    fn drain_into<F: FnMut(RgbaImage) -> Result<()>>(&mut self, on_frame: &mut F) -> Result<()> {
+        self.frames.drain(..).try_for_each(on_frame)
+    }
+
+    /// Repeats the last frame for every output tick before `time`
+    fn emit_until(&mut self, time: f64) -> Result<(), ffmpeg::Error> {
+        loop {
+            let tick = self.start + self.emitted as f64 / self.framerate as f64;
+            let Some((_, frame, scaled)) = self.last.as_mut().filter(|_| tick < time) else {
+                return Ok(());
+            };
+            if scaled.is_none() {
+                *scaled = Some(scale_frame(&mut self.scaler, frame, self.aspect.scaled_size((frame.width(), frame.height()), (self.frame_width, self.frame_height)))?);
+            }
+            self.frames.push(scaled.clone().unwrap());
+            self.emitted += 1;
+        }
+    }
+}
+
+/// Scales a frame to an RGBA image of the given size
+fn scale_frame(scaler: &mut Option<scaling::Context>, frame: &Video, (scaled_width, scaled_height): (u32, u32)) -> Result<RgbaImage, ffmpeg::Error> {
+    let (width, height) = (frame.width(), frame.height());
+    // Streams can change resolution midway, which needs a new scaler
+    if scaler.as_ref().is_none_or(|scaler| scaler.input().width != width || scaler.input().height != height || scaler.input().format != frame.format()) {
+        *scaler = Some(scaling::Context::get(frame.format(), width, height, Pixel::RGBA, scaled_width, scaled_height, scaling::Flags::BICUBIC)?);
+    }
+    let scaler = scaler.as_mut().unwrap();
+    let mut rgba = Video::empty();
+    scaler.run(frame, &mut rgba)?;
+
+    let (stride, data) = (rgba.stride(0), rgba.data(0));
+    let row_length = scaled_width as usize * 4;
+    let mut pixels = Vec::with_capacity(row_length * scaled_height as usize);
+    for row in 0..scaled_height as usize {
+        pixels.extend_from_slice(&data[row * stride..row * stride + row_length]);
+    }
+    RgbaImage::from_raw(scaled_width, scaled_height, pixels).ok_or(ffmpeg::Error::InvalidData)
+}
+
+/// Collects the trimmed audio as mono 16 bit samples, filling gaps with silence to keep it in sync with the video
+struct SampleCollector {
+    start: f64,
+    end: f64,
+    time_base: Rational,
+    /// Start time of the earliest stream, subtracted from the sample times
+    offset: f64,
+    resampler: Option<ffmpeg::software::resampling::Context>,
+    rate: u32,
+    samples: Vec<i16>,
+    done: bool,
+}
+
+impl SampleCollector {
+    fn new(start: f64, end: f64, time_base: Rational, offset: f64) -> Self {
+        SampleCollector { start, end, time_base, offset, resampler: None, rate: 0, samples: Vec::new(), done: false }
+    }
+
+    fn push(&mut self, frame: &Audio) -> Result<(), ffmpeg::Error> {
+        if self.resampler.is_none() {
+            self.rate = frame.rate();
+            self.resampler = Some(frame.resampler(Sample::I16(sample::Type::Packed), ChannelLayout::MONO, frame.rate())?);
+        }
+        let mut resampled = Audio::empty();
+        self.resampler.as_mut().unwrap().run(frame, &mut resampled)?;
+        let samples: Vec<i16> = resampled.data(0)[..resampled.samples() * 2]
+            .chunks_exact(2)
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect();
+
+        let rate = self.rate as f64;
+        let first = match frame.timestamp().or(frame.pts()) {
+            Some(timestamp) => ((timestamp as f64 * f64::from(self.time_base) - self.offset - self.start) * rate).round() as i64,
+            None => self.samples.len() as i64
+        };
+        let last = ((self.end - self.start) * rate).min(i64::MAX as f64) as i64;
+        for (index, sample) in samples.into_iter().enumerate() {
+            let position = first + index as i64;
+            if position >= last {
+                self.done = true;
+                break;
+            }
+            if position >= self.samples.len() as i64 {
+                self.samples.resize(position as usize, 0);
+                self.samples.push(sample);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_wav(path: &Path, rate: u32, samples: &[i16]) -> std::io::Result<()> {
+    let data_size = samples.len() as u32 * 2;
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_size).to_le_bytes())?;
+    writer.write_all(b"WAVEfmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    // PCM, mono
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&rate.to_le_bytes())?;
+    writer.write_all(&(rate * 2).to_le_bytes())?;
+    writer.write_all(&2u16.to_le_bytes())?;
+    writer.write_all(&16u16.to_le_bytes())?;
+    writer.write_all(b"data")?;
+    writer.write_all(&data_size.to_le_bytes())?;
+    for sample in samples {
+        writer.write_all(&sample.to_le_bytes())?;
+    }
+    writer.flush()
+}
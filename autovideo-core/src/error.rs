@@ -0,0 +1,66 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use serde::Serialize;
+
+pub type Result<T, E = AutovideoError> = std::result::Result<T, E>;
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum AutovideoError {
+    /// ffmpeg or ffprobe could not be started
+    FfmpegMissing { program: String, message: String },
+    /// ffprobe ran but could not read the input
+    ProbeFailed { input: PathBuf },
+    /// ffmpeg ran but did not produce usable output
+    ConversionFailed { input: PathBuf, message: String },
+    VideoTooLong { video: String, max_length: String, grids: usize },
+    DuplicateName { name: String },
+    /// Two different names ended up with the same internal identifier
+    NameCollision { name: String, other: String, identifier: String },
+    InvalidInput { message: String },
+    /// `path` is `None` for plugins generated from scratch
+    InvalidEsp { path: Option<PathBuf>, message: String },
+    InvalidManifest { path: PathBuf, message: String },
+    /// An existing BA2 archive that new files were added to could not be read
+    InvalidArchive { path: PathBuf, message: String },
+    Image { path: PathBuf, message: String },
+    Audio { path: PathBuf, message: String },
+    Io { path: PathBuf, message: String },
+    /// Stopped through a [`CancellationToken`](crate::CancellationToken)
+    Cancelled,
+}
+
+impl fmt::Display for AutovideoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AutovideoError::FfmpegMissing { program, message } => write!(f, "{message}: {program} is not installed!"),
+            AutovideoError::ProbeFailed { input } => write!(f, "Failed to probe video for audio track: {}", input.display()),
+            AutovideoError::ConversionFailed { input, message } => write!(f, "{message}: {}", input.display()),
+            AutovideoError::VideoTooLong { video, max_length, grids } => write!(f, "Video {video} is longer than {max_length} ({grids} grids). Reduce FPS or use a shorter video."),
+            AutovideoError::DuplicateName { name } => write!(f, "Cannot have two videos with the same name: {name}"),
+            AutovideoError::NameCollision { name, other, identifier } => write!(f, "Videos {other} and {name} would share the identifier {identifier}, rename one of them"),
+            AutovideoError::InvalidInput { message } => write!(f, "{message}"),
+            AutovideoError::InvalidEsp { path: Some(path), message } => write!(f, "Esp {} is invalid: {message}", path.display()),
+            AutovideoError::InvalidEsp { path: None, message } => write!(f, "Failed to build esp: {message}"),
+            AutovideoError::InvalidManifest { path, message } => write!(f, "Manifest {} is invalid: {message}", path.display()),
+            AutovideoError::InvalidArchive { path, message } => write!(f, "Archive {} is invalid: {message}", path.display()),
+            AutovideoError::Image { path, message } => write!(f, "Failed to process image {}: {message}", path.display()),
+            AutovideoError::Audio { path, message } => write!(f, "Failed to encode audio {}: {message}", path.display()),
+            AutovideoError::Io { path, message } => write!(f, "{}: {message}", path.display()),
+            AutovideoError::Cancelled => write!(f, "Conversion was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for AutovideoError {}
+
+pub(crate) trait PathContext<T> {
+    /// Attaches the path that was being worked on to an io error
+    fn at(self, path: impl AsRef<Path>) -> Result<T>;
+}
+
+impl<T> PathContext<T> for std::io::Result<T> {
+    fn at(self, path: impl AsRef<Path>) -> Result<T> {
+        self.map_err(|e| AutovideoError::Io { path: path.as_ref().to_path_buf(), message: e.to_string() })
+    }
+}
@@ -0,0 +1,420 @@
+use std::io::{Read, Write};
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+const HEADER_VERSION: f32 = 0.95;
+const FORM_VERSION: u16 = 131;
+const FIRST_OBJECT_ID: u32 = 0x800;
+const COMPRESSED_FLAG: u32 = 0x00040000;
+
+pub struct Subrecord {
+    pub signature: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+impl Subrecord {
+    pub fn new(signature: &[u8; 4], data: Vec<u8>) -> Self {
+        Subrecord { signature: *signature, data }
+    }
+
+    pub fn zstring(signature: &[u8; 4], value: &str) -> Self {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        Subrecord::new(signature, data)
+    }
+
+    pub fn u32(signature: &[u8; 4], value: u32) -> Self {
+        Subrecord::new(signature, value.to_le_bytes().to_vec())
+    }
+
+    pub fn as_zstring(&self) -> Option<&str> {
+        std::str::from_utf8(self.data.strip_suffix(&[0]).unwrap_or(&self.data)).ok()
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.data.get(0..4)?.try_into().ok()?))
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        // Subrecords bigger than a u16 can hold are preceded by an XXXX subrecord carrying the real size
+        if self.data.len() > u16::MAX as usize {
+            out.extend_from_slice(b"XXXX");
+            out.extend_from_slice(&4u16.to_le_bytes());
+            out.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+            out.extend_from_slice(&self.signature);
+            out.extend_from_slice(&0u16.to_le_bytes());
+        } else {
+            out.extend_from_slice(&self.signature);
+            out.extend_from_slice(&(self.data.len() as u16).to_le_bytes());
+        }
+        out.extend_from_slice(&self.data);
+    }
+
+    fn parse_all(mut data: &[u8]) -> Result<Vec<Subrecord>, String> {
+        let mut subrecords = vec![];
+        let mut next_size = None;
+        while !data.is_empty() {
+            let signature: [u8; 4] = read_array(data, 0)?;
+            let size = next_size.take().unwrap_or(u16::from_le_bytes(read_array(data, 4)?) as usize);
+            let value = data.get(6..6 + size).ok_or("Subrecord runs past the end of its record")?;
+            if &signature == b"XXXX" {
+                next_size = Some(u32::from_le_bytes(read_array(value, 0)?) as usize);
+            } else {
+                subrecords.push(Subrecord::new(&signature, value.to_vec()));
+            }
+            data = &data[6 + size..];
+        }
+        Ok(subrecords)
+    }
+}
+
+pub struct Record {
+    pub signature: [u8; 4],
+    pub flags: u32,
+    pub form_id: u32,
+    pub version_control: [u8; 4],
+    pub form_version: u16,
+    pub subrecords: Vec<Subrecord>,
+}
+
+impl Record {
+    pub fn new(signature: &[u8; 4], form_id: u32, subrecords: Vec<Subrecord>) -> Self {
+        Record { signature: *signature, flags: 0, form_id, version_control: [0; 4], form_version: FORM_VERSION, subrecords }
+    }
+
+    pub fn subrecord(&self, signature: &[u8; 4]) -> Option<&Subrecord> {
+        self.subrecords.iter().find(|s| &s.signature == signature)
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let mut data = vec![];
+        for subrecord in &self.subrecords {
+            subrecord.write(&mut data);
+        }
+        if self.flags & COMPRESSED_FLAG != 0 {
+            let mut encoder = ZlibEncoder::new((data.len() as u32).to_le_bytes().to_vec(), Compression::default());
+            encoder.write_all(&data).expect("Writing to a Vec cannot fail");
+            data = encoder.finish().expect("Writing to a Vec cannot fail");
+        }
+        out.extend_from_slice(&self.signature);
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.flags.to_le_bytes());
+        out.extend_from_slice(&self.form_id.to_le_bytes());
+        out.extend_from_slice(&self.version_control);
+        out.extend_from_slice(&self.form_version.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(&data);
+    }
+
+    fn parse(data: &[u8]) -> Result<(Record, usize), String> {
+        let signature: [u8; 4] = read_array(data, 0)?;
+        let size = u32::from_le_bytes(read_array(data, 4)?) as usize;
+        let flags = u32::from_le_bytes(read_array(data, 8)?);
+        let body = data.get(24..24 + size).ok_or(format!("{} record runs past the end of the file", String::from_utf8_lossy(&signature)))?;
+        let subrecords = if flags & COMPRESSED_FLAG != 0 {
+            let mut decompressed = vec![];
+            ZlibDecoder::new(body.get(4..).unwrap_or_default()).read_to_end(&mut decompressed)
+                .map_err(|e| format!("Failed to decompress {} record: {e}", String::from_utf8_lossy(&signature)))?;
+            Subrecord::parse_all(&decompressed)?
+        } else {
+            Subrecord::parse_all(body)?
+        };
+        let record = Record {
+            signature,
+            flags,
+            form_id: u32::from_le_bytes(read_array(data, 12)?),
+            version_control: read_array(data, 16)?,
+            form_version: u16::from_le_bytes(read_array(data, 20)?),
+            subrecords,
+        };
+        Ok((record, 24 + size))
+    }
+}
+
+pub enum GroupEntry {
+    Record(Record),
+    Group(Group),
+}
+
+pub struct Group {
+    pub label: [u8; 4],
+    pub group_type: i32,
+    pub version_control: [u8; 8],
+    pub entries: Vec<GroupEntry>,
+}
+
+impl Group {
+    pub fn records(&self) -> impl Iterator<Item = &Record> {
+        self.entries.iter().filter_map(|e| match e {
+            GroupEntry::Record(record) => Some(record),
+            GroupEntry::Group(_) => None,
+        })
+    }
+
+    pub fn records_mut(&mut self) -> impl Iterator<Item = &mut Record> {
+        self.entries.iter_mut().filter_map(|e| match e {
+            GroupEntry::Record(record) => Some(record),
+            GroupEntry::Group(_) => None,
+        })
+    }
+
+    /// Amount of records and groups in this group, itself included
+    fn count(&self) -> usize {
+        1 + self.entries.iter().map(|e| match e {
+            GroupEntry::Record(_) => 1,
+            GroupEntry::Group(group) => group.count(),
+        }).sum::<usize>()
+    }
+
+    fn all_records(&self) -> Vec<&Record> {
+        self.entries.iter().flat_map(|e| match e {
+            GroupEntry::Record(record) => vec![record],
+            GroupEntry::Group(group) => group.all_records(),
+        }).collect()
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let mut data = vec![];
+        for entry in &self.entries {
+            match entry {
+                GroupEntry::Record(record) => record.write(&mut data),
+                GroupEntry::Group(group) => group.write(&mut data),
+            }
+        }
+        out.extend_from_slice(b"GRUP");
+        out.extend_from_slice(&(data.len() as u32 + 24).to_le_bytes());
+        out.extend_from_slice(&self.label);
+        out.extend_from_slice(&self.group_type.to_le_bytes());
+        out.extend_from_slice(&self.version_control);
+        out.extend_from_slice(&data);
+    }
+
+    fn parse(data: &[u8]) -> Result<(Group, usize), String> {
+        let size = u32::from_le_bytes(read_array(data, 4)?) as usize;
+        let mut body = data.get(24..size).ok_or("Group runs past the end of the file")?;
+        let mut entries = vec![];
+        while !body.is_empty() {
+            let (entry, length) = parse_entry(body)?;
+            entries.push(entry);
+            body = &body[length..];
+        }
+        let group = Group {
+            label: read_array(data, 8)?,
+            group_type: i32::from_le_bytes(read_array(data, 12)?),
+            version_control: read_array(data, 16)?,
+            entries,
+        };
+        Ok((group, size))
+    }
+}
+
+/// A TES4 plugin made of top level record groups. The HEDR of the header record is regenerated on write
+pub struct Plugin {
+    pub header: Record,
+    pub next_object_id: u32,
+    pub groups: Vec<Group>,
+}
+
+impl Plugin {
+    pub fn new(author: &str, description: Option<&str>, masters: &[&str]) -> Self {
+        let mut subrecords = vec![Subrecord::zstring(b"CNAM", author)];
+        if let Some(description) = description {
+            subrecords.push(Subrecord::zstring(b"SNAM", description));
+        }
+        for master in masters {
+            subrecords.push(Subrecord::zstring(b"MAST", master));
+            subrecords.push(Subrecord::new(b"DATA", vec![0; 8]));
+        }
+        subrecords.push(Subrecord::u32(b"INTV", 1));
+        Plugin {
+            header: Record::new(b"TES4", 0, subrecords),
+            next_object_id: FIRST_OBJECT_ID,
+            groups: vec![],
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        if data.get(0..4) != Some(b"TES4") {
+            return Err("File is not a Fallout 4 plugin".to_string());
+        }
+        let (header, mut position) = Record::parse(data)?;
+        // Version, record count and next object id, the version is kept on write
+        if header.subrecord(b"HEDR").is_some_and(|hedr| hedr.data.len() < 12) {
+            return Err("The HEDR of the plugin header is shorter than 12 bytes".to_string());
+        }
+        let mut groups = vec![];
+        while position < data.len() {
+            match parse_entry(&data[position..])? {
+                (GroupEntry::Group(group), length) => {
+                    groups.push(group);
+                    position += length;
+                }
+                (GroupEntry::Record(record), _) => return Err(format!("Found {} record outside of a group", String::from_utf8_lossy(&record.signature)))
+            }
+        }
+        let mut plugin = Plugin { header, next_object_id: FIRST_OBJECT_ID, groups };
+
+        // Never trust the stored next object id alone, hand edited plugins often have it lower than their highest FormID
+        let local_index = plugin.master_count();
+        let stored_next_id = plugin.header.subrecord(b"HEDR").map(|h| u32::from_le_bytes(h.data[8..12].try_into().unwrap()));
+        let highest_id = plugin.groups.iter()
+            .flat_map(|g| g.all_records())
+            .filter(|r| r.form_id >> 24 == local_index)
+            .map(|r| (r.form_id & 0xFFFFFF) + 1)
+            .max();
+        plugin.next_object_id = [Some(FIRST_OBJECT_ID), stored_next_id, highest_id].into_iter().flatten().max().unwrap();
+        Ok(plugin)
+    }
+
+    pub fn master_count(&self) -> u32 {
+        self.header.subrecords.iter().filter(|s| &s.signature == b"MAST").count() as u32
+    }
+
+    /// Load order index of the given master, relative to this plugin
+    pub fn master_index(&self, master: &str) -> Option<u32> {
+        self.header.subrecords.iter()
+            .filter(|s| &s.signature == b"MAST")
+            .position(|s| s.as_zstring().is_some_and(|m| m.eq_ignore_ascii_case(master)))
+            .map(|index| index as u32)
+    }
+
+    /// Returns a new FormID local to this plugin
+    pub fn allocate_form_id(&mut self) -> u32 {
+        let form_id = (self.master_count() << 24) | self.next_object_id;
+        self.next_object_id += 1;
+        form_id
+    }
+
+    /// Returns the group for the given record type, appending an empty one if it does not exist yet
+    pub fn group_mut(&mut self, label: &[u8; 4]) -> &mut Group {
+        let index = match self.groups.iter().position(|g| &g.label == label) {
+            Some(index) => index,
+            None => {
+                self.groups.push(Group { label: *label, group_type: 0, version_control: [0; 8], entries: vec![] });
+                self.groups.len() - 1
+            }
+        };
+        &mut self.groups[index]
+    }
+
+    pub fn add_record(&mut self, record: Record) {
+        let signature = record.signature;
+        self.group_mut(&signature).entries.push(GroupEntry::Record(record));
+    }
+
+    pub fn records(&self, signature: [u8; 4]) -> impl Iterator<Item = &Record> {
+        self.groups.iter().filter(move |g| g.label == signature).flat_map(|g| g.records())
+    }
+
+    pub fn record_by_form_id_mut(&mut self, signature: &[u8; 4], form_id: u32) -> Option<&mut Record> {
+        self.groups.iter_mut()
+            .filter(|g| &g.label == signature)
+            .flat_map(|g| g.records_mut())
+            .find(|r| r.form_id == form_id)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let groups: Vec<_> = self.groups.iter().filter(|g| !g.entries.is_empty()).collect();
+        let record_count: usize = groups.iter().map(|g| g.count()).sum();
+
+        let mut hedr = match self.header.subrecord(b"HEDR") {
+            Some(hedr) => hedr.data[0..4].to_vec(),
+            None => HEADER_VERSION.to_le_bytes().to_vec(),
+        };
+        hedr.extend_from_slice(&(record_count as u32).to_le_bytes());
+        hedr.extend_from_slice(&self.next_object_id.to_le_bytes());
+        let mut header_subrecords = vec![Subrecord::new(b"HEDR", hedr)];
+        header_subrecords.extend(self.header.subrecords.iter()
+            .filter(|s| &s.signature != b"HEDR")
+            .map(|s| Subrecord::new(&s.signature, s.data.clone())));
+        let header = Record {
+            signature: *b"TES4",
+            flags: self.header.flags,
+            form_id: 0,
+            version_control: self.header.version_control,
+            form_version: self.header.form_version,
+            subrecords: header_subrecords,
+        };
+
+        let mut out = vec![];
+        header.write(&mut out);
+        for group in groups {
+            group.write(&mut out);
+        }
+        out
+    }
+}
+
+fn parse_entry(data: &[u8]) -> Result<(GroupEntry, usize), String> {
+    if data.get(0..4) == Some(b"GRUP") {
+        let (group, length) = Group::parse(data)?;
+        Ok((GroupEntry::Group(group), length))
+    } else {
+        let (record, length) = Record::parse(data)?;
+        Ok((GroupEntry::Record(record), length))
+    }
+}
+
+fn read_array<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N], String> {
+    data.get(offset..offset + N)
+        .map(|bytes| bytes.try_into().unwrap())
+        .ok_or("Unexpected end of esp data".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin() -> Plugin {
+        let mut plugin = Plugin::new("Author", Some("Description"), &["Fallout4.esm", "VotWMaster.esm"]);
+        let form_id = plugin.allocate_form_id();
+        plugin.add_record(Record::new(b"KYWD", form_id, vec![Subrecord::zstring(b"EDID", "Keyword"), Subrecord::u32(b"TNAM", 9)]));
+        let form_id = plugin.allocate_form_id();
+        plugin.add_record(Record::new(b"FLST", form_id, vec![Subrecord::zstring(b"EDID", "List"), Subrecord::u32(b"LNAM", 0x0100_0800)]));
+        plugin
+    }
+
+    #[test]
+    fn written_plugins_parse_back_to_the_same_bytes() {
+        let bytes = plugin().to_bytes();
+        let parsed = Plugin::parse(&bytes).unwrap();
+        assert_eq!(parsed.to_bytes(), bytes);
+        assert_eq!(parsed.master_index("votwmaster.esm"), Some(1));
+        assert_eq!(parsed.records(*b"KYWD").next().and_then(|r| r.subrecord(b"EDID")).and_then(Subrecord::as_zstring), Some("Keyword"));
+    }
+
+    #[test]
+    fn new_form_ids_follow_the_existing_ones() {
+        let mut parsed = Plugin::parse(&plugin().to_bytes()).unwrap();
+        assert_eq!(parsed.allocate_form_id(), (2 << 24) | (FIRST_OBJECT_ID + 2));
+    }
+
+    #[test]
+    fn large_subrecords_round_trip() {
+        let mut plugin = plugin();
+        let form_id = plugin.allocate_form_id();
+        plugin.add_record(Record::new(b"NOTE", form_id, vec![Subrecord::new(b"DATA", vec![5; 70_000])]));
+        let parsed = Plugin::parse(&plugin.to_bytes()).unwrap();
+        assert_eq!(parsed.records(*b"NOTE").next().and_then(|r| r.subrecord(b"DATA")).map(|s| s.data.len()), Some(70_000));
+    }
+
+    #[test]
+    fn empty_groups_are_left_out() {
+        let mut plugin = plugin();
+        plugin.group_mut(b"COBJ");
+        assert!(Plugin::parse(&plugin.to_bytes()).unwrap().groups.iter().all(|g| &g.label != b"COBJ"));
+    }
+
+    #[test]
+    fn short_headers_are_rejected() {
+        let mut bytes = plugin().to_bytes();
+        // Cut the HEDR right after the record header down to its first 2 bytes
+        assert_eq!(&bytes[24..30], b"HEDR\x0c\x00");
+        bytes.splice(30..40, []);
+        bytes[28] = 2;
+        let size = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) - 10;
+        bytes[4..8].copy_from_slice(&size.to_le_bytes());
+        assert!(Plugin::parse(&bytes).is_err_and(|e| e.contains("HEDR")));
+    }
+}
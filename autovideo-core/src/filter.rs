@@ -0,0 +1,82 @@
+use crate::aspect::AspectMode;
+use crate::job::Trim;
+
+/// Builds the `-filter_complex` graph that turns a video into the frames and audio of a mod in a single ffmpeg pass.
+/// Video filters are chained from the first video stream to `[video]`, audio filters from the first audio stream to `[audio]`
+#[derive(Default)]
+pub struct FilterGraph {
+    video: Vec<String>,
+    audio: Vec<String>,
+}
+
+impl FilterGraph {
+    pub fn new() -> Self {
+        FilterGraph::default()
+    }
+
+    /// Keeps the trimmed part of the video and its audio, starting both at 0 again
+    pub fn trim(mut self, trim: &Trim) -> Self {
+        let mut range = format!("start={}", trim.start.unwrap_or(0.0));
+        if let Some(end) = trim.end {
+            range += &format!(":end={end}");
+        }
+        self.video.extend([format!("trim={range}"), "setpts=PTS-STARTPTS".to_string()]);
+        self.audio.extend([format!("atrim={range}"), "asetpts=PTS-STARTPTS".to_string()]);
+        self
+    }
+
+    /// Repeats or drops frames to get the given framerate
+    pub fn fps(mut self, framerate: u32) -> Self {
+        self.video.push(format!("fps={framerate}"));
+        self
+    }
+
+    pub fn scale(mut self, width: u32, height: u32) -> Self {
+        self.video.push(format!("scale={width}:{height}"));
+        self
+    }
+
+    /// Scales without changing the aspect ratio, to fit inside the given size or to cover all of it
+    pub fn scale_keeping_aspect(mut self, width: u32, height: u32, cover: bool) -> Self {
+        let fit = if cover { "increase" } else { "decrease" };
+        self.video.push(format!("scale={width}:{height}:force_original_aspect_ratio={fit}"));
+        self
+    }
+
+    /// Centers the video on an area of the given size and RGB colour
+    pub fn pad(mut self, width: u32, height: u32, [red, green, blue]: [u8; 3]) -> Self {
+        self.video.push(format!("pad={width}:{height}:(ow-iw)/2:(oh-ih)/2:color=0x{red:02x}{green:02x}{blue:02x}"));
+        self
+    }
+
+    /// Cuts the given size out of the center of the video
+    pub fn crop(mut self, width: u32, height: u32) -> Self {
+        self.video.push(format!("crop={width}:{height}"));
+        self
+    }
+
+    /// Brings the video to frames of the given size as the aspect mode says.
+    /// Smart crops need the whole covering frame to pick their part, so they are only scaled
+    pub fn aspect(self, aspect: AspectMode, width: u32, height: u32) -> Self {
+        match aspect {
+            AspectMode::Stretch => self.scale(width, height),
+            AspectMode::Pad { color } => self.scale_keeping_aspect(width, height, false).pad(width, height, color),
+            AspectMode::Crop => self.scale_keeping_aspect(width, height, true).crop(width, height),
+            AspectMode::SmartCrop => self.scale_keeping_aspect(width, height, true),
+        }
+    }
+
+    /// The graph for `-filter_complex`, with the audio chain left out for videos without sound
+    pub fn build(&self, audio: bool) -> String {
+        let chain = |input: &str, filters: &[String], passthrough: &str, output: &str| {
+            let filters = if filters.is_empty() { passthrough.to_string() } else { filters.join(",") };
+            format!("[0:{input}:0]{filters}[{output}]")
+        };
+        let mut graph = chain("v", &self.video, "null", "video");
+        if audio {
+            graph += ";";
+            graph += &chain("a", &self.audio, "anull", "audio");
+        }
+        graph
+    }
+}
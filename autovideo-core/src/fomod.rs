@@ -0,0 +1,155 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::error::{PathContext, Result};
+use crate::grid::GridLayout;
+use crate::job::PreparedVideo;
+use crate::records::VOTW_MASTER;
+use crate::{mesh_folder, sound_folder, texture_folder, Screen};
+
+/// Folders holding the textures of one frame size start with this, followed by the size
+const TEXTURE_VARIANT_PREFIX: &str = "Textures ";
+
+/// Folder below the output folder the textures of the given frame size go in, when building an installer
+pub fn texture_variant_folder(size: u32) -> String {
+    format!("{TEXTURE_VARIANT_PREFIX}{size}")
+}
+
+/// Writes `fomod/info.xml` and `fomod/ModuleConfig.xml` into the output folder, so mod managers can install it as it is.
+/// The TV and Projector files are always installed, the DriveIn esp and meshes are an option and every frame size
+/// built into the output folder so far becomes a texture quality to choose from, described by the frames the videos have at that size
+pub fn write_installer(output_dir: &Path, mod_name: &str, mod_identifier: &str, plugin_name: &str, drive_in_plugin_name: Option<&str>, videos: &[PreparedVideo], layout: GridLayout) -> Result<()> {
+    let plugin_stem = Path::new(plugin_name).file_stem().unwrap_or_default().to_string_lossy();
+    // Esps made by the FO4Edit script and files packed into archives are not in the output folder, only what is there gets installed
+    let existing = |paths: Vec<PathBuf>| paths.into_iter().filter(|path| output_dir.join(path).exists()).collect::<Vec<_>>();
+    let mut required = existing(vec![
+        PathBuf::from(plugin_name),
+        PathBuf::from(format!("{plugin_stem} - Main.ba2")),
+        sound_folder(mod_identifier),
+        mesh_folder(Screen::Television, mod_identifier),
+        mesh_folder(Screen::Projector, mod_identifier),
+    ]);
+    let drive_in = existing(drive_in_plugin_name.map(PathBuf::from).into_iter().chain([mesh_folder(Screen::DriveIn, mod_identifier)]).collect());
+    // Without a DriveIn esp of its own there is nothing to choose, the meshes are for a DriveIn esp made some other way
+    let drive_in_option = drive_in_plugin_name.is_some_and(|name| output_dir.join(name).is_file());
+    if !drive_in_option {
+        required.extend(drive_in.iter().cloned());
+    }
+    let variants = texture_variants(output_dir).at(output_dir)?;
+    if variants.is_empty() {
+        required.extend(existing(vec![PathBuf::from(format!("{plugin_stem} - Textures.ba2")), texture_folder(mod_identifier)]));
+    }
+
+    let mut required_files = String::new();
+    for path in &required {
+        required_files += &install_entry(output_dir, path, path, 2);
+    }
+    if let [(_, folder)] = variants.as_slice() {
+        required_files += &install_entry(output_dir, folder, Path::new(""), 2);
+    }
+
+    let mut steps = String::new();
+    if drive_in_option {
+        let files: String = drive_in.iter().map(|path| install_entry(output_dir, path, path, 8)).collect();
+        steps += &install_step("Screens", "Screens", &[
+            ("TV and Projector".to_string(), "Shows the videos on TVs and projectors".to_string(), String::new()),
+            ("TV, Projector and DriveIn".to_string(), "Also shows the videos that are short enough on the screen of the Starlight Drive-In".to_string(), files),
+        ]);
+    }
+    if variants.len() > 1 {
+        let options: Vec<_> = variants.iter().map(|(size, folder)| (
+            format!("{size} pixel wide frames"),
+            format!("Videos made of {} pixel frames. Larger frames look sharper, but need more video memory", frame_sizes(videos, layout, *size)),
+            install_entry(output_dir, folder, Path::new(""), 8)
+        )).collect();
+        steps += &install_step("Texture Quality", "Frame size", &options);
+    }
+
+    let fomod_dir = output_dir.join("fomod");
+    fs::create_dir_all(&fomod_dir).at(&fomod_dir)?;
+    let mod_name = escape(mod_name);
+    let info = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<fomod>
+\t<Name>{mod_name}</Name>
+\t<Description>Videos for Videos of the Wasteland</Description>
+</fomod>
+");
+    let info_path = fomod_dir.join("info.xml");
+    fs::write(&info_path, info).at(&info_path)?;
+
+    let steps = if steps.is_empty() { String::new() } else { format!("\t<installSteps order=\"Explicit\">\n{steps}\t</installSteps>\n") };
+    let config = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<config xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xsi:noNamespaceSchemaLocation=\"http://qconsulting.ca/fo3/ModConfig5.0.xsd\">
+\t<moduleName>{mod_name}</moduleName>
+\t<moduleDependencies operator=\"And\">
+\t\t<fileDependency file=\"{VOTW_MASTER}\" state=\"Active\"/>
+\t</moduleDependencies>
+\t<requiredInstallFiles>
+{required_files}\t</requiredInstallFiles>
+{steps}</config>
+");
+    let config_path = fomod_dir.join("ModuleConfig.xml");
+    fs::write(&config_path, config).at(&config_path)
+}
+
+/// Frame sizes built into the output folder and their texture folders, largest first
+fn texture_variants(output_dir: &Path) -> std::io::Result<Vec<(u32, PathBuf)>> {
+    let mut variants = vec![];
+    for entry in fs::read_dir(output_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let size = name.strip_prefix(TEXTURE_VARIANT_PREFIX).and_then(|size| size.parse::<u32>().ok());
+        if let (Some(size), true) = (size, entry.path().is_dir()) {
+            variants.push((size, PathBuf::from(name)));
+        }
+    }
+    variants.sort_by(|(a, _), (b, _)| b.cmp(a));
+    Ok(variants)
+}
+
+/// Width and height of the frames of the videos at a frame size, like `512x384`, listing each shape once when their aspects differ
+fn frame_sizes(videos: &[PreparedVideo], layout: GridLayout, size: u32) -> String {
+    let mut frames: Vec<(u32, u32)> = videos.iter()
+        .map(|video| layout.geometry(size, video.aspect.four_by_three()))
+        .map(|geometry| (geometry.frame_width, geometry.frame_height))
+        .collect();
+    frames.sort_unstable_by(|a, b| b.cmp(a));
+    frames.dedup();
+    frames.iter().map(|(width, height)| format!("{width}x{height}")).collect::<Vec<_>>().join(" and ")
+}
+
+/// An installer page where exactly one of the options is picked, given by name, description and the files it installs
+fn install_step(step: &str, group: &str, options: &[(String, String, String)]) -> String {
+    let mut plugins = String::new();
+    for (index, (name, description, files)) in options.iter().enumerate() {
+        // The first option is what most people want, the others are picked on purpose
+        let kind = if index == 0 { "Recommended" } else { "Optional" };
+        let files = if files.is_empty() { String::new() } else { format!("\t\t\t\t\t\t\t<files>\n{files}\t\t\t\t\t\t\t</files>\n") };
+        plugins += &format!("\t\t\t\t\t\t<plugin name=\"{}\">
+\t\t\t\t\t\t\t<description>{}</description>
+{files}\t\t\t\t\t\t\t<typeDescriptor>
+\t\t\t\t\t\t\t\t<type name=\"{kind}\"/>
+\t\t\t\t\t\t\t</typeDescriptor>
+\t\t\t\t\t\t</plugin>
+", escape(name), escape(description));
+    }
+    format!("\t\t<installStep name=\"{}\">
+\t\t\t<optionalFileGroups order=\"Explicit\">
+\t\t\t\t<group name=\"{}\" type=\"SelectExactlyOne\">
+\t\t\t\t\t<plugins order=\"Explicit\">
+{plugins}\t\t\t\t\t</plugins>
+\t\t\t\t</group>
+\t\t\t</optionalFileGroups>
+\t\t</installStep>
+", escape(step), escape(group))
+}
+
+/// A `file` or `folder` element copying a path below the output folder to a path below the game's Data folder
+fn install_entry(output_dir: &Path, source: &Path, destination: &Path, indent: usize) -> String {
+    let element = if output_dir.join(source).is_dir() { "folder" } else { "file" };
+    let path = |path: &Path| escape(&path.to_string_lossy().replace('/', "\\"));
+    format!("{}<{element} source=\"{}\" destination=\"{}\"/>\n", "\t".repeat(indent), path(source), path(destination))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
@@ -0,0 +1,332 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread::Scope;
+use image::{imageops, RgbaImage};
+use serde::{Deserialize, Serialize};
+use crate::error::{AutovideoError, Result};
+use crate::texture::{save_as_dds, TextureOptions};
+
+/// Largest texture Fallout 4 loads, in pixels per side
+pub const MAX_TEXTURE_SIZE: u32 = 16384;
+/// Full grids that may wait for or be in the encoder at once, over all videos and frame sizes.
+/// Each one holds a whole grid texture in memory
+const MAX_PENDING_GRIDS: usize = 3;
+
+/// How frames are arranged on a grid texture, filled row by row.
+/// More frames per grid mean fewer grid textures for a video, but fewer pixels per frame at the same texture size
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GridLayout {
+    pub columns: u32,
+    pub rows: u32,
+}
+
+impl GridLayout {
+    pub fn frames_per_grid(&self) -> usize {
+        (self.columns * self.rows) as usize
+    }
+
+    /// Width and height of a grid texture with frames of the given size
+    pub fn texture_size(&self, frame_size: u32) -> (u32, u32) {
+        (frame_size.saturating_mul(self.columns), frame_size.saturating_mul(self.rows))
+    }
+
+    /// The frames of a video on grids of this layout. 4:3 frames keep the texture size of square ones and fit in more rows
+    pub fn geometry(&self, frame_size: u32, four_by_three: bool) -> GridGeometry {
+        let (width, height) = self.texture_size(frame_size);
+        let frame_height = if four_by_three { (frame_size * 3 / 4).max(1) } else { frame_size };
+        GridGeometry { frame_width: frame_size, frame_height, columns: self.columns, rows: height / frame_height, width, height }
+    }
+}
+
+/// Size and position of the frames on the grid textures of a single video
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GridGeometry {
+    pub frame_width: u32,
+    pub frame_height: u32,
+    pub columns: u32,
+    pub rows: u32,
+    /// Size of the texture, which may leave some space below the last row
+    pub width: u32,
+    pub height: u32,
+}
+
+impl GridGeometry {
+    pub fn frames_per_grid(&self) -> usize {
+        (self.columns * self.rows) as usize
+    }
+}
+
+impl Default for GridLayout {
+    fn default() -> Self {
+        GridLayout { columns: 16, rows: 16 }
+    }
+}
+
+impl fmt::Display for GridLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.columns, self.rows)
+    }
+}
+
+/// Parses a layout written as `COLUMNSxROWS`, like `8x8`
+impl FromStr for GridLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{s} is not a grid layout like 16x16");
+        let (columns, rows) = s.split_once(['x', 'X']).ok_or_else(invalid)?;
+        Ok(GridLayout {
+            columns: columns.trim().parse().map_err(|_| invalid())?,
+            rows: rows.trim().parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Encodes the full grids of every video of a conversion on a few shared threads, so at most [`MAX_PENDING_GRIDS`]
+/// of them are held in memory besides the grid each [`GridWriter`] is filling
+pub struct GridEncoder {
+    state: Mutex<EncoderState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct EncoderState {
+    queue: VecDeque<EncodeJob>,
+    /// Grids queued or being encoded
+    pending: usize,
+    writers: Vec<WriterState>,
+    /// No more grids are coming, the threads stop once the queue is empty
+    closed: bool,
+}
+
+#[derive(Default)]
+struct WriterState {
+    pending: usize,
+    encoded: usize,
+    /// First failed grid of the writer, an error or the panic of its encoder
+    failure: Option<Failure>,
+}
+
+enum Failure {
+    Error(AutovideoError),
+    Panic(Box<dyn Any + Send>),
+}
+
+struct EncodeJob {
+    writer: usize,
+    grid: RgbaImage,
+    path: PathBuf,
+    texture: TextureOptions,
+}
+
+impl GridEncoder {
+    pub fn new() -> Self {
+        GridEncoder { state: Mutex::new(EncoderState::default()), changed: Condvar::new() }
+    }
+
+    /// Starts the encoder threads on a scope. They end once the encoder is [closed](GridEncoder::close) and all grids are written
+    pub fn start<'scope>(&'scope self, scope: &'scope Scope<'scope, '_>) {
+        // More threads would wait for grids that are never there
+        for _ in 0..MAX_PENDING_GRIDS {
+            scope.spawn(|| self.run());
+        }
+    }
+
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.changed.notify_all();
+    }
+
+    fn run(&self) {
+        let mut state = self.lock();
+        loop {
+            let Some(job) = state.queue.pop_front() else {
+                if state.closed {
+                    return;
+                }
+                state = self.changed.wait(state).unwrap();
+                continue;
+            };
+            drop(state);
+            let EncodeJob { writer, grid, path, texture } = job;
+            let result = panic::catch_unwind(AssertUnwindSafe(|| save_as_dds(&grid, &path, &texture)));
+            drop(grid);
+            state = self.lock();
+            state.pending -= 1;
+            let writer = &mut state.writers[writer];
+            writer.pending -= 1;
+            match result {
+                Ok(Ok(())) => writer.encoded += 1,
+                Ok(Err(e)) => { writer.failure.get_or_insert(Failure::Error(e)); }
+                Err(panic) => { writer.failure.get_or_insert(Failure::Panic(panic)); }
+            }
+            self.changed.notify_all();
+        }
+    }
+
+    fn add_writer(&self) -> usize {
+        let mut state = self.lock();
+        state.writers.push(WriterState::default());
+        state.writers.len() - 1
+    }
+
+    /// Queues a grid once there is room for it, failing instead when an earlier grid of the writer failed
+    fn submit(&self, job: EncodeJob) -> Result<()> {
+        let mut state = self.lock();
+        while state.pending >= MAX_PENDING_GRIDS && state.writers[job.writer].failure.is_none() {
+            state = self.changed.wait(state).unwrap();
+        }
+        state.writers[job.writer].failure.take().map_or(Ok(()), Failure::raise)?;
+        state.pending += 1;
+        state.writers[job.writer].pending += 1;
+        state.queue.push_back(job);
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Waits until all grids of a writer are written, returning the first of them that failed
+    fn wait(&self, writer: usize) -> Option<Failure> {
+        let mut state = self.lock();
+        while state.writers[writer].pending > 0 {
+            state = self.changed.wait(state).unwrap();
+        }
+        state.writers[writer].failure.take()
+    }
+
+    fn encoded(&self, writer: usize) -> usize {
+        self.lock().writers[writer].encoded
+    }
+
+    fn lock(&self) -> MutexGuard<'_, EncoderState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for GridEncoder {
+    fn default() -> Self {
+        GridEncoder::new()
+    }
+}
+
+impl Failure {
+    /// Returns the error, or continues the panic on the thread of the writer
+    fn raise(self) -> Result<()> {
+        match self {
+            Failure::Error(e) => Err(e),
+            Failure::Panic(panic) => panic::resume_unwind(panic),
+        }
+    }
+}
+
+/// Assembles frames into grid textures as they arrive, handing every grid to the encoder as soon as it is full.
+/// Dropping a writer before it is finished drops its last grid and waits for the others, so their files can be removed
+pub struct GridWriter<'a, F: FnMut(usize) -> Result<()>> {
+    geometry: GridGeometry,
+    grids_path: PathBuf,
+    texture: TextureOptions,
+    /// Called with the amount of full grids before starting the next one
+    on_new_grid: F,
+    grid: Option<RgbaImage>,
+    frame_count: usize,
+    encoder: &'a GridEncoder,
+    /// Index of this writer in the encoder
+    id: usize,
+}
+
+impl<'a, F: FnMut(usize) -> Result<()>> GridWriter<'a, F> {
+    pub fn new(encoder: &'a GridEncoder, geometry: GridGeometry, grids_path: PathBuf, texture: TextureOptions, on_new_grid: F) -> Self {
+        GridWriter { geometry, grids_path, texture, on_new_grid, grid: None, frame_count: 0, id: encoder.add_writer(), encoder }
+    }
+
+    pub fn push(&mut self, frame: &RgbaImage) -> Result<()> {
+        let GridGeometry { frame_width, frame_height, columns, width, height, .. } = self.geometry;
+        let frames_per_grid = self.geometry.frames_per_grid();
+        let index = self.frame_count % frames_per_grid;
+        if index == 0 && self.frame_count > 0 {
+            (self.on_new_grid)(self.frame_count / frames_per_grid)?;
+        }
+        let grid = self.grid.get_or_insert_with(|| RgbaImage::from_pixel(width, height, [0, 0, 0, 255].into()));
+        let x_offset = (index as u32 % columns) * frame_width;
+        let y_offset = (index as u32 / columns) * frame_height;
+        imageops::replace(grid, frame, x_offset as i64, y_offset as i64);
+        self.frame_count += 1;
+        if self.frame_count.is_multiple_of(frames_per_grid) {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Waits for all grids to be written, returning the amount of grids and the amount of frames in the last one
+    pub fn finish(mut self) -> Result<(usize, usize)> {
+        self.flush()?;
+        self.encoder.wait(self.id).map_or(Ok(()), Failure::raise)?;
+        let frames_per_grid = self.geometry.frames_per_grid();
+        let grid_amount = self.frame_count.div_ceil(frames_per_grid);
+        Ok((grid_amount, self.frame_count - (grid_amount.max(1) - 1) * frames_per_grid))
+    }
+
+    /// Grids written to disk so far
+    pub fn grids_encoded(&self) -> usize {
+        self.encoder.encoded(self.id)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let Some(grid) = self.grid.take() else {
+            return Ok(());
+        };
+        let grid_number = self.frame_count.div_ceil(self.geometry.frames_per_grid());
+        let path = self.grids_path.join(grid_file_name(grid_number));
+        self.encoder.submit(EncodeJob { writer: self.id, grid, path, texture: self.texture })
+    }
+}
+
+impl<F: FnMut(usize) -> Result<()>> Drop for GridWriter<'_, F> {
+    fn drop(&mut self) {
+        self.encoder.wait(self.id);
+    }
+}
+
+/// File name of a grid texture, counting from 1
+pub fn grid_file_name(grid: usize) -> String {
+    format!("Grid{grid:0>2}.dds")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::thread;
+    use super::*;
+
+    #[test]
+    fn writers_share_the_encoder() {
+        let dir = std::env::temp_dir().join(format!("autovideo-grid-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let geometry = GridLayout { columns: 2, rows: 2 }.geometry(4, false);
+        let frame = RgbaImage::from_pixel(4, 4, [255, 0, 0, 255].into());
+        let encoder = GridEncoder::new();
+        thread::scope(|scope| {
+            encoder.start(scope);
+            let mut written = GridWriter::new(&encoder, geometry, dir.clone(), TextureOptions::default(), |_| Ok(()));
+            let mut failing = GridWriter::new(&encoder, geometry, dir.join("missing"), TextureOptions::default(), |_| Ok(()));
+            for _ in 0..4 * MAX_PENDING_GRIDS + 1 {
+                written.push(&frame).unwrap();
+            }
+            // The first grid fails in the background, which shows up when the writer is done
+            for _ in 0..4 {
+                failing.push(&frame).unwrap();
+            }
+            assert!(failing.finish().is_err());
+            assert_eq!(written.finish().unwrap(), (MAX_PENDING_GRIDS + 1, 1));
+            encoder.close();
+        });
+        for grid in 1..=MAX_PENDING_GRIDS + 1 {
+            assert!(dir.join(grid_file_name(grid)).is_file());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
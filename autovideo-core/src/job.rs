@@ -0,0 +1,538 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::aspect::AspectMode;
+use crate::error::{AutovideoError, PathContext, Result};
+use crate::grid::{GridGeometry, GridLayout, MAX_TEXTURE_SIZE};
+use crate::texture::{TextureEstimate, TextureOptions};
+use crate::utility::identifier;
+use crate::{AudioFormat, OutputOptions, ScriptInfo};
+
+/// Everything needed to convert a set of videos into a VotW mod
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConversionJob {
+    /// Name of the mod, shown in-game on the holotapes and reels
+    pub mod_name: String,
+    pub videos: Vec<VideoJob>,
+    #[serde(default)]
+    pub options: JobOptions,
+}
+
+/// Settings shared by all videos of a job
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JobOptions {
+    /// Existing esp to append the videos to, a new one is generated when not set
+    pub input_esp: Option<PathBuf>,
+    /// Existing DriveIn esp to append the videos to, a new one is generated when not set
+    pub input_esp_drive_in: Option<PathBuf>,
+    /// Framerate of videos that don't set their own
+    pub framerate: u32,
+    /// Width and height of a single frame in the grid textures
+    pub size: u32,
+    /// Frame sizes of screens that get grids of their own instead of the ones of `size`
+    pub screen_sizes: ScreenSizes,
+    /// Frames per row and column of the grid textures
+    pub layout: GridLayout,
+    /// How videos are fit into the frames of the grids. Grids of 4:3 frames have more rows than the layout
+    pub aspect: AspectMode,
+    /// Generates a FO4Edit script instead of esps, asking for the script info on stdin when `script_info` is not set
+    pub generate_script: bool,
+    pub script_info: Option<ScriptInfo>,
+    /// Format, encoder speed and mipmaps of the grid textures
+    pub texture: TextureOptions,
+    pub audio_format: AudioFormat,
+    /// Videos converted at the same time. Two keep ffmpeg and the texture encoder busy together, more mostly need more memory
+    pub parallel_videos: usize,
+    pub output: OutputOptions,
+}
+
+impl Default for JobOptions {
+    fn default() -> Self {
+        JobOptions {
+            input_esp: None,
+            input_esp_drive_in: None,
+            framerate: 10,
+            size: 512,
+            screen_sizes: ScreenSizes::default(),
+            layout: GridLayout::default(),
+            aspect: AspectMode::default(),
+            generate_script: false,
+            script_info: None,
+            texture: TextureOptions::default(),
+            audio_format: AudioFormat::default(),
+            parallel_videos: 2,
+            output: OutputOptions::default(),
+        }
+    }
+}
+
+impl JobOptions {
+    /// Size and encoding time of every grid texture with the job's size, layout and texture options
+    pub fn texture_estimate(&self) -> TextureEstimate {
+        let (width, height) = self.layout.texture_size(self.size);
+        self.texture.estimate(width, height)
+    }
+}
+
+/// A single video of a job, with optional overrides of the job settings
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VideoJob {
+    pub path: PathBuf,
+    /// Name shown in-game, defaults to the file name
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Defaults to the framerate in the file name (video.30fps.mp4), then to the job framerate
+    #[serde(default)]
+    pub framerate: Option<u32>,
+    #[serde(default)]
+    pub trim: Option<Trim>,
+    /// Overrides the job's `aspect` for this video
+    #[serde(default)]
+    pub aspect: Option<AspectMode>,
+}
+
+impl VideoJob {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        VideoJob { path: path.into(), name: None, framerate: None, trim: None, aspect: None }
+    }
+}
+
+/// Part of the input to convert, in seconds
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Trim {
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+}
+
+/// The screens of VotW, every video gets a mesh for each of them
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum Screen {
+    Television,
+    Projector,
+    /// Only shows videos of at most 8 grids
+    DriveIn,
+}
+
+impl Screen {
+    pub const ALL: [Screen; 3] = [Screen::Television, Screen::Projector, Screen::DriveIn];
+
+    /// Name of the screen in the mesh folders
+    pub fn name(&self) -> &'static str {
+        match self {
+            Screen::Television => "Television",
+            Screen::Projector => "Projector",
+            Screen::DriveIn => "DriveIn",
+        }
+    }
+
+    /// Most grids the mesh of the screen can show, longer videos don't play on it at all
+    pub fn max_grids(&self) -> Option<usize> {
+        match self {
+            Screen::Television | Screen::Projector => None,
+            Screen::DriveIn => Some(8),
+        }
+    }
+}
+
+/// Frame size of the grids of every screen, screens without one use the size of the job
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScreenSizes {
+    pub television: Option<u32>,
+    pub projector: Option<u32>,
+    pub drive_in: Option<u32>,
+}
+
+impl ScreenSizes {
+    pub fn size(&self, screen: Screen, default: u32) -> u32 {
+        match screen {
+            Screen::Television => self.television,
+            Screen::Projector => self.projector,
+            Screen::DriveIn => self.drive_in,
+        }.unwrap_or(default)
+    }
+}
+
+/// A video with all of its defaults filled in
+pub(crate) struct PreparedVideo {
+    pub name: String,
+    pub identifier: String,
+    pub path: PathBuf,
+    pub framerate: u32,
+    pub trim: Option<Trim>,
+    pub aspect: AspectMode,
+    /// Geometry the frames are decoded at, that of the largest grid set
+    pub geometry: GridGeometry,
+    /// Grids of every frame size the screens use, largest first
+    pub grid_sets: Vec<GridSet>,
+}
+
+/// Grid textures of a video at one frame size, shared by the screens using that size
+pub(crate) struct GridSet {
+    pub geometry: GridGeometry,
+    /// Folder of the grids below the folder of the video, those of the job's size are placed in the video folder itself
+    pub folder: Option<String>,
+    pub screens: Vec<Screen>,
+}
+
+impl PreparedVideo {
+    pub fn grid_set(&self, screen: Screen) -> &GridSet {
+        self.grid_sets.iter().find(|set| set.screens.contains(&screen)).expect("Every screen has a grid set")
+    }
+
+    /// Frames the video turns into at its framerate, for an input of the given length in seconds
+    pub fn frame_amount(&self, duration: f64) -> usize {
+        let (start, end) = match self.trim {
+            Some(Trim { start, end }) => (start.unwrap_or(0.0), end.map_or(duration, |end| end.min(duration))),
+            None => (0.0, duration)
+        };
+        ((end - start).max(0.0) * self.framerate as f64).ceil() as usize
+    }
+}
+
+impl ConversionJob {
+    pub fn builder(mod_name: impl Into<String>) -> ConversionJobBuilder {
+        ConversionJobBuilder {
+            job: ConversionJob { mod_name: mod_name.into(), videos: Vec::new(), options: JobOptions::default() }
+        }
+    }
+
+    /// Reads a job manifest, a TOML or JSON file holding a serialized job.
+    /// Relative video and esp paths are resolved against the folder of the manifest
+    pub fn from_manifest(path: &Path) -> Result<ConversionJob> {
+        let invalid = |message: String| AutovideoError::InvalidManifest { path: path.to_path_buf(), message };
+        let contents = fs::read_to_string(path).at(path)?;
+        let extension = path.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+        let mut job: ConversionJob = match extension.as_str() {
+            "toml" => toml::from_str(&contents).map_err(|e| invalid(e.to_string()))?,
+            "json" => serde_json::from_str(&contents).map_err(|e| invalid(e.to_string()))?,
+            _ => return Err(invalid("Manifest must be a .toml or .json file".to_string()))
+        };
+        let base = path.parent().unwrap_or(Path::new(""));
+        for video in &mut job.videos {
+            video.path = base.join(&video.path);
+        }
+        for esp in [&mut job.options.input_esp, &mut job.options.input_esp_drive_in].into_iter().flatten() {
+            *esp = base.join(&*esp);
+        }
+        Ok(job)
+    }
+
+    /// Checks the job settings and video names without touching any video
+    pub fn validate(&self) -> Result<()> {
+        self.prepare().map(|_| ())
+    }
+
+    pub(crate) fn prepare(&self) -> Result<Vec<PreparedVideo>> {
+        let options = &self.options;
+        if self.mod_name.trim().is_empty() {
+            return Err(AutovideoError::InvalidInput { message: "Mod name cannot be empty".to_string() });
+        }
+        if self.videos.is_empty() {
+            return Err(AutovideoError::InvalidInput { message: "No videos to convert".to_string() });
+        }
+        if options.parallel_videos == 0 {
+            return Err(AutovideoError::InvalidInput { message: "At least one video has to be converted at a time".to_string() });
+        }
+        if options.layout.columns == 0 || options.layout.rows == 0 {
+            return Err(AutovideoError::InvalidInput { message: format!("Grid layout {} needs at least one column and row", options.layout) });
+        }
+        // Largest first, the frames are decoded at that size and scaled down for the others
+        let mut sizes: Vec<u32> = Screen::ALL.iter().map(|screen| options.screen_sizes.size(*screen, options.size)).collect();
+        sizes.sort_unstable_by(|a, b| b.cmp(a));
+        sizes.dedup();
+        for &size in &sizes {
+            if size == 0 || (size & (size - 1)) != 0 {
+                return Err(AutovideoError::InvalidInput { message: format!("{size} is not a power of 2 (e.g. 128, 256, 512)") });
+            }
+            let (width, height) = options.layout.texture_size(size);
+            if width > MAX_TEXTURE_SIZE || height > MAX_TEXTURE_SIZE {
+                return Err(AutovideoError::InvalidInput {
+                    message: format!("Grid textures of {width}x{height} are larger than Fallout 4 supports ({MAX_TEXTURE_SIZE}), use a smaller size or fewer frames per grid")
+                })
+            }
+        }
+
+        let mut videos = Vec::with_capacity(self.videos.len());
+        for video in &self.videos {
+            if !video.path.is_file() {
+                return Err(AutovideoError::InvalidInput { message: format!("Video file does not exist: {}", video.path.display()) });
+            }
+            let (file_name, file_framerate) = name_and_framerate(&video.path);
+            let name = video.name.clone().unwrap_or(file_name).trim().to_string();
+            let framerate = video.framerate.or(file_framerate).unwrap_or(options.framerate);
+            if name.is_empty() {
+                return Err(AutovideoError::InvalidInput { message: "Video names cannot be empty".to_string() });
+            }
+            if framerate == 0 {
+                return Err(AutovideoError::InvalidInput { message: format!("Framerate of {name} must be at least 1") });
+            }
+            if let Some(Trim { start, end }) = video.trim {
+                let start = start.unwrap_or(0.0);
+                if start < 0.0 || end.is_some_and(|end| end <= start) {
+                    return Err(AutovideoError::InvalidInput { message: format!("Trim range of {name} is empty or negative") });
+                }
+            }
+            let aspect = video.aspect.unwrap_or(options.aspect);
+            let grid_sets: Vec<GridSet> = sizes.iter().map(|&size| GridSet {
+                geometry: options.layout.geometry(size, aspect.four_by_three()),
+                folder: (size != options.size).then(|| size.to_string()),
+                screens: Screen::ALL.into_iter().filter(|screen| options.screen_sizes.size(*screen, options.size) == size).collect(),
+            }).collect();
+            // All meshes of a video play the same amount of grids
+            if grid_sets.iter().any(|set| set.geometry.frames_per_grid() != grid_sets[0].geometry.frames_per_grid()) {
+                return Err(AutovideoError::InvalidInput { message: format!("Frame sizes {sizes:?} of {name} don't fit the same amount of frames in a grid") });
+            }
+            videos.push(PreparedVideo {
+                identifier: identifier(&name),
+                name,
+                path: video.path.clone(),
+                framerate,
+                trim: video.trim,
+                aspect,
+                geometry: grid_sets[0].geometry,
+                grid_sets,
+            });
+        }
+        for (index, video) in videos.iter().enumerate() {
+            if videos.iter().position(|v| v.name == video.name).unwrap() != index {
+                return Err(AutovideoError::DuplicateName { name: video.name.clone() })
+            }
+            if let Some(other) = videos.iter().find(|v| v.name != video.name && v.identifier.eq_ignore_ascii_case(&video.identifier)) {
+                return Err(AutovideoError::NameCollision { name: video.name.clone(), other: other.name.clone(), identifier: video.identifier.clone() })
+            }
+        }
+        Ok(videos)
+    }
+}
+
+/// Name and framerate from a file name like `video.30fps.mp4`
+fn name_and_framerate(path: &Path) -> (String, Option<u32>) {
+    let name = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let split: Vec<&str> = name.split('.').collect();
+    if split.len() > 1 {
+        if let Some(Ok(fps)) = split[split.len()-1].strip_suffix("fps").map(str::parse::<u32>) {
+            return (split[0..split.len()-1].join("_"), Some(fps));
+        }
+    }
+    (name, None)
+}
+
+pub struct ConversionJobBuilder {
+    job: ConversionJob,
+}
+
+impl ConversionJobBuilder {
+    pub fn video(mut self, video: VideoJob) -> Self {
+        self.job.videos.push(video);
+        self
+    }
+
+    pub fn videos(mut self, videos: impl IntoIterator<Item = VideoJob>) -> Self {
+        self.job.videos.extend(videos);
+        self
+    }
+
+    pub fn input_esp(mut self, input_esp: Option<PathBuf>) -> Self {
+        self.job.options.input_esp = input_esp;
+        self
+    }
+
+    pub fn input_esp_drive_in(mut self, input_esp_drive_in: Option<PathBuf>) -> Self {
+        self.job.options.input_esp_drive_in = input_esp_drive_in;
+        self
+    }
+
+    pub fn framerate(mut self, framerate: u32) -> Self {
+        self.job.options.framerate = framerate;
+        self
+    }
+
+    pub fn size(mut self, size: u32) -> Self {
+        self.job.options.size = size;
+        self
+    }
+
+    pub fn screen_sizes(mut self, screen_sizes: ScreenSizes) -> Self {
+        self.job.options.screen_sizes = screen_sizes;
+        self
+    }
+
+    pub fn layout(mut self, layout: GridLayout) -> Self {
+        self.job.options.layout = layout;
+        self
+    }
+
+    pub fn aspect(mut self, aspect: AspectMode) -> Self {
+        self.job.options.aspect = aspect;
+        self
+    }
+
+    pub fn generate_script(mut self, generate_script: bool) -> Self {
+        self.job.options.generate_script = generate_script;
+        self
+    }
+
+    pub fn script_info(mut self, script_info: Option<ScriptInfo>) -> Self {
+        self.job.options.script_info = script_info;
+        self
+    }
+
+    pub fn texture(mut self, texture: TextureOptions) -> Self {
+        self.job.options.texture = texture;
+        self
+    }
+
+    pub fn audio_format(mut self, audio_format: AudioFormat) -> Self {
+        self.job.options.audio_format = audio_format;
+        self
+    }
+
+    pub fn parallel_videos(mut self, parallel_videos: usize) -> Self {
+        self.job.options.parallel_videos = parallel_videos;
+        self
+    }
+
+    pub fn output(mut self, output: OutputOptions) -> Self {
+        self.job.options.output = output;
+        self
+    }
+
+    /// Validates the job, see [`ConversionJob::validate`]
+    pub fn build(self) -> Result<ConversionJob> {
+        self.job.validate()?;
+        Ok(self.job)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A folder with empty video files of the given names, which is all validation looks at
+    fn video_dir(test: &str, files: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("autovideo-job-{}-{test}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for file in files {
+            fs::write(dir.join(file), []).unwrap();
+        }
+        dir
+    }
+
+    fn invalid_input(result: Result<ConversionJob>) -> String {
+        match result {
+            Err(AutovideoError::InvalidInput { message }) => message,
+            Err(e) => panic!("Expected invalid input, got {e}"),
+            Ok(_) => panic!("Expected invalid input, the job was accepted"),
+        }
+    }
+
+    fn trimmed(path: PathBuf, start: Option<f64>, end: Option<f64>) -> VideoJob {
+        VideoJob { trim: Some(Trim { start, end }), ..VideoJob::new(path) }
+    }
+
+    #[test]
+    fn empty_jobs_are_rejected() {
+        assert_eq!(invalid_input(ConversionJob::builder("Mod").build()), "No videos to convert");
+        let dir = video_dir("empty", &["Video.mp4"]);
+        assert_eq!(invalid_input(ConversionJob::builder("  ").video(VideoJob::new(dir.join("Video.mp4"))).build()), "Mod name cannot be empty");
+        let missing = invalid_input(ConversionJob::builder("Mod").video(VideoJob::new(dir.join("Missing.mp4"))).build());
+        assert!(missing.starts_with("Video file does not exist"));
+        let unnamed = VideoJob { name: Some(" ".to_string()), ..VideoJob::new(dir.join("Video.mp4")) };
+        assert_eq!(invalid_input(ConversionJob::builder("Mod").video(unnamed).build()), "Video names cannot be empty");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_trims_are_rejected() {
+        let dir = video_dir("trims", &["Video.mp4"]);
+        let video = dir.join("Video.mp4");
+        for (start, end) in [(Some(-1.0), None), (Some(5.0), Some(5.0)), (Some(10.0), Some(2.0)), (None, Some(0.0))] {
+            let message = invalid_input(ConversionJob::builder("Mod").video(trimmed(video.clone(), start, end)).build());
+            assert_eq!(message, "Trim range of Video is empty or negative", "{start:?}..{end:?}");
+        }
+        for (start, end) in [(None, Some(2.0)), (Some(3.0), None), (Some(0.5), Some(0.6))] {
+            assert!(ConversionJob::builder("Mod").video(trimmed(video.clone(), start, end)).build().is_ok(), "{start:?}..{end:?}");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn duplicate_names_are_rejected() {
+        let dir = video_dir("names", &["Video.mp4", "Video.mkv", "Video.30fps.avi", "Other.mp4"]);
+        let job = |files: &[&str]| ConversionJob::builder("Mod").videos(files.iter().map(|file| VideoJob::new(dir.join(file)))).build();
+        assert!(matches!(job(&["Video.mp4", "Video.mkv"]), Err(AutovideoError::DuplicateName { name }) if name == "Video"));
+        // The framerate is not part of the name
+        assert!(matches!(job(&["Video.mp4", "Video.30fps.avi"]), Err(AutovideoError::DuplicateName { name }) if name == "Video"));
+        assert!(job(&["Video.mp4", "Other.mp4"]).is_ok());
+        let renamed = ConversionJob::builder("Mod")
+            .video(VideoJob::new(dir.join("Video.mp4")))
+            .video(VideoJob { name: Some("Another".to_string()), ..VideoJob::new(dir.join("Video.mkv")) })
+            .build();
+        assert!(renamed.is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn videos_get_their_framerate_from_the_file_name() {
+        let dir = video_dir("framerate", &["Clip.30fps.mp4", "Video.mp4"]);
+        let job = ConversionJob::builder("Mod")
+            .video(VideoJob::new(dir.join("Clip.30fps.mp4")))
+            .video(VideoJob { framerate: Some(5), ..VideoJob::new(dir.join("Video.mp4")) })
+            .framerate(12)
+            .build()
+            .unwrap();
+        let videos = job.prepare().unwrap();
+        assert_eq!((videos[0].name.as_str(), videos[0].framerate), ("Clip", 30));
+        assert_eq!((videos[1].name.as_str(), videos[1].framerate), ("Video", 5));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn manifest_paths_are_relative_to_the_manifest() {
+        let dir = video_dir("manifest", &["Video.mp4"]);
+        fs::create_dir_all(dir.join("clips")).unwrap();
+        fs::write(dir.join("clips/Clip.mp4"), []).unwrap();
+        let absolute = dir.join("Video.mp4");
+        let toml = format!("mod_name = \"Mod\"
+[[videos]]
+path = \"clips/Clip.mp4\"
+trim = {{ start = 1.5 }}
+[[videos]]
+path = {absolute:?}
+name = \"Named\"
+[options]
+input_esp = \"plugins/Mod.esp\"
+framerate = 15
+");
+        let manifest = dir.join("job.toml");
+        fs::write(&manifest, toml).unwrap();
+        let job = ConversionJob::from_manifest(&manifest).unwrap();
+        assert_eq!(job.videos[0].path, dir.join("clips/Clip.mp4"));
+        assert_eq!(job.videos[1].path, absolute);
+        assert_eq!(job.options.input_esp, Some(dir.join("plugins/Mod.esp")));
+        assert_eq!(job.options.input_esp_drive_in, None);
+        assert_eq!((job.options.framerate, job.options.size), (15, 512));
+        let videos = job.prepare().unwrap();
+        assert_eq!((videos[0].name.as_str(), videos[1].name.as_str()), ("Clip", "Named"));
+
+        let json = dir.join("job.json");
+        fs::write(&json, r#"{"mod_name": "Mod", "videos": [{"path": "Video.mp4", "framerate": 24}]}"#).unwrap();
+        let job = ConversionJob::from_manifest(&json).unwrap();
+        assert_eq!((job.videos[0].path.clone(), job.videos[0].framerate), (absolute, Some(24)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_manifests_are_rejected() {
+        let dir = video_dir("invalid-manifest", &[]);
+        for (file, contents) in [("job.yaml", "mod_name: Mod"), ("job.toml", "videos = []"), ("job.json", "{\"mod_name\": 5, \"videos\": []}")] {
+            let path = dir.join(file);
+            fs::write(&path, contents).unwrap();
+            assert!(matches!(ConversionJob::from_manifest(&path), Err(AutovideoError::InvalidManifest { path: error_path, .. }) if error_path == path), "{file}");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,18 +1,49 @@
-#![allow(clippy::too_many_arguments)]
-
 mod utility;
+mod aspect;
+mod error;
 mod convert;
 mod scriptwrite;
+mod esp;
+mod records;
+mod job;
+mod grid;
+mod audio;
+mod ba2;
+mod build;
+mod cancel;
+mod fomod;
+mod nif;
+mod plan;
+mod probe;
+mod progress;
+mod texture;
+mod mesh;
+#[cfg(feature = "native-decode")]
+mod decode;
+#[cfg(not(feature = "native-decode"))]
+mod filter;
 
-use std::cmp::Ordering;
 use std::fs;
-use std::fs::File;
-use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use crate::utility::{count_strings_in_bytes, elongate, find_and_replace_float, replace_all_strings_in_bytes, replace_first_string_in_bytes, user_input};
+use crate::ba2::ArchiveKind;
+use crate::build::BuildManifest;
+use crate::convert::ConvertedVideo;
+use crate::error::PathContext;
+use crate::esp::Plugin;
+use crate::records::PluginVideo;
+use crate::utility::identifier;
+
+pub use crate::aspect::AspectMode;
+pub use crate::audio::AudioFormat;
+pub use crate::cancel::CancellationToken;
+pub use crate::error::{AutovideoError, Result};
+pub use crate::grid::GridLayout;
+pub use crate::job::{ConversionJob, ConversionJobBuilder, JobOptions, Screen, ScreenSizes, Trim, VideoJob};
+pub use crate::plan::{plan_videos, Plan, ScreenPlan, VideoPlan};
+pub use crate::progress::{Count, ProgressEvent, Stage};
+pub use crate::texture::{EncoderSpeed, TextureEstimate, TextureFormat, TextureOptions};
 
-#[derive(serde::Deserialize)]
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct ScriptInfo {
     esp_name: String,
     tv_record: String,
@@ -20,207 +51,277 @@ pub struct ScriptInfo {
     di_esp_name: String,
 }
 
-pub enum Mode {
-    YES,
-    NO,
-    UiMode
+/// Where a conversion puts its results and its temporary files
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct OutputOptions {
+    /// Root of the generated mod, textures, meshes, sounds and esps are placed below it
+    pub output_dir: PathBuf,
+    /// Scratch space for extracted frames and helper executables. Every video gets its own folder in here
+    pub cache_dir: PathBuf,
+    /// Packs the textures, meshes and sounds into BA2 archives named after the esp, which the game loads along with it.
+    /// Files of earlier runs that are in the archives already stay in them
+    pub archives: bool,
+    /// Writes a FOMOD installer into the output folder. Textures then go below a `Textures <size>` folder,
+    /// so running again with another size adds a texture quality to choose from
+    pub fomod: bool,
+    /// Skips converting videos that were converted into the output folder before, with the same input, settings and version.
+    /// Their meshes and esp records are still made again. Inputs are only hashed again when their size or modification time changed.
+    /// Without it the inputs aren't hashed at all, and the build manifest only loses the videos that were converted again,
+    /// so other videos of the folder can still be skipped later
+    pub incremental: bool,
 }
 
-pub fn process_videos<F: FnMut()>(
-    inputs: Vec<PathBuf>,
-    input_esp: Option<PathBuf>,
-    input_esp_drive_in: Option<PathBuf>,
-    mod_name: String,
-    input_framerate: u32,
-    short_names: bool,
-    video_name: Option<String>,
-    size: u32,
-    keep_aspect_ratio: bool,
-    generate_script: bool,
-    script_info: Option<ScriptInfo>,
-    mode: Mode,
-    mut checkpoint_reached: F,
-    high_quality: bool
-) -> Result<(), String> {
-    let mut videos = vec![];
-    let path_to_name_and_framerate = |path: &PathBuf| -> (String, u32) {
-        let mut name = path.file_stem().unwrap().to_str().unwrap().to_string();
-        let mut framerate = input_framerate;
-        let split: Vec<&str> = name.split('.').collect();
-        if split.len() > 1 && split[split.len()-1].ends_with("fps") {
-            if let Ok(fps) = split[split.len()-1].strip_suffix("fps").unwrap().parse::<u32>() {
-                framerate = fps;
-                name = split[0..split.len()-1].join("_");
-            }
-        }
-        if short_names && name.len() > 10 {
-            name = name[0..10].to_string();
+impl Default for OutputOptions {
+    fn default() -> Self {
+        OutputOptions {
+            output_dir: PathBuf::from("output"),
+            cache_dir: std::env::temp_dir().join("autovideo cache"),
+            archives: false,
+            fomod: false,
+            incremental: true,
         }
-        (name.replace(' ', "_"), framerate)
-    };
-    let only_one = inputs.len() == 1;
-    for input in inputs {
-        let (filename, file_framerate) = path_to_name_and_framerate(&input);
-        let name = if only_one {
-            video_name.clone().unwrap_or(filename)
-        } else {
-            filename
-        };
-        videos.push((name, input, file_framerate));
     }
-    if !generate_script && videos.len() > 10 {
-        if matches!(mode, Mode::UiMode) {
-            return Err(format!("Your provided {} videos but an esp can only support 10, you can surpass this limit by generating an xEdit script.", videos.len()))
+}
+
+impl OutputOptions {
+    /// Folder the textures of the given frame size are placed below, instead of the output folder itself when building an installer
+    pub fn texture_root(&self, size: u32) -> PathBuf {
+        if self.fomod {
+            self.output_dir.join(fomod::texture_variant_folder(size))
         } else {
-            let message = format!("You provided {} videos but an esp can only support 10, continue? (y/N) ", videos.len());
-            if matches!(mode, Mode::YES) {
-                println!("{message}Y");
-            } else if user_input(&message).to_lowercase() != "y" {
-                return Err("Too many videos".to_string());
-            }
-        }
-    }
-    for (index, (name, _, _)) in videos.iter().enumerate() {
-        if name.len() > 10 {
-            return Err(format!("Name {} is too long. Max 10 characters! Rename the video / use --video_name when using a single video / use --short-names.", name));
+            self.output_dir.clone()
         }
-        if videos.iter().position(|(n, _, _)| n == name).unwrap() != index {
-            return Err(format!("Cannot have two videos with the same name: {}", name))
-        }
-    }
-    if (size & (size - 1)) != 0 {
-        return Err(format!("{} is not a power of 2 (e.g. 128, 256, 512)", size));
-    }
-    if size > 1024 {
-        return Err("It is not recommended to have a frame size over 1024".to_string())
     }
+}
+
+/// Folder of the sounds of a mod, relative to the output folder
+fn sound_folder(mod_identifier: &str) -> PathBuf {
+    Path::new("Sound/Videos").join(mod_identifier)
+}
 
+/// Folder of the meshes of a mod for one of the screens, relative to the output folder
+fn mesh_folder(screen: Screen, mod_identifier: &str) -> PathBuf {
+    Path::new("meshes/Videos").join(screen.name()).join(mod_identifier)
+}
 
+/// Folder of the grid textures of a mod, relative to [`OutputOptions::texture_root`]
+fn texture_folder(mod_identifier: &str) -> PathBuf {
+    Path::new("textures/Videos").join(mod_identifier)
+}
 
-    let mut tv_esp_bytes = if let Some(input_esp) = input_esp {
-        if input_esp.exists() && input_esp.is_file() && input_esp.extension().unwrap().eq_ignore_ascii_case("esp") {
-            let mut bytes = vec![];
-            File::open(input_esp).unwrap().read_to_end(&mut bytes).unwrap();
-            bytes
-        } else {
-            return Err("Given esp file does not exist".to_string());
-        }
-    } else {
-        include_bytes!("./assets/TemplateVideos_10.esp").into()
-    };
-    let mut di_esp_bytes = if let Some(input_esp) = input_esp_drive_in {
-        if input_esp.exists() && input_esp.is_file() && input_esp.extension().unwrap().eq_ignore_ascii_case("esp") {
-            let mut bytes = vec![];
-            File::open(input_esp).unwrap().read_to_end(&mut bytes).unwrap();
-            bytes
-        } else {
-            return Err("Given DriveIn esp file does not exist".to_string());
-        }
-    } else {
-        include_bytes!("./assets/TemplateDriveIn_10.esp").into()
-    };
+pub enum Mode {
+    YES,
+    NO,
+    UiMode
+}
+
+/// Converts all videos of a job and writes the mod, telling `on_progress` what it is busy with along the way.
+/// Cancelling stops the conversions at the next frame or grid and removes the textures and sound of the videos they were converting
+pub fn process_videos<F: FnMut(ProgressEvent) + Send>(job: &ConversionJob, mode: Mode, cancel: &CancellationToken, mut on_progress: F) -> Result<()> {
+    let videos = job.prepare()?;
+    let options = &job.options;
+    let mod_name = job.mod_name.trim().to_string();
+    let generate_script = options.generate_script || options.script_info.is_some();
+
+    let mod_identifier = identifier(&mod_name);
+
+    let mut plugins = Plugins::load(options, &mod_identifier, &mod_name)?;
 
 
 
     let mut write_drivein_esp = false;
     let mut script_video_data = Vec::new();
 
-    let elongated_mod_identifier = elongate(&mod_name, 'X', 10, true)?;
-    let leading_spaced_mod_identifier = elongate(&mod_name, ' ', 10, true)?;
-    let trailing_spaced_mod_identifier = elongate(&mod_name, ' ', 10, false)?;
-    
-    let has_nvenc = if keep_aspect_ratio {
-        match Command::new("ffmpeg").args([
-            "-f", "lavfi",
-            "-t", "1",
-            "-i", "testsrc=r=30:s=1280x720",
-            "-c:v", "hevc_nvenc",
-            "-f", "null",
-            "-"
-        ]).status() {
-            Ok(status) => status.success(),
-            Err(_) => false
-        }
-    } else { false };
 
-    for (video_name, video_path, video_framerate) in videos {
-        let elongated_video_identifier = elongate(&video_name, 'X', 10, true)?;
-        let trailing_spaced_video_identifier = elongate(&video_name, ' ', 10, false)?;
+    let output_dir = &options.output.output_dir;
+    let mut build = BuildManifest::load(output_dir);
+    // Hashing reads changed inputs in full, which is only worth it when unchanged videos are skipped
+    let fingerprints = videos.iter()
+        .map(|video| options.output.incremental.then(|| build.fingerprint(&mod_identifier, video, options)).transpose())
+        .collect::<Result<Vec<_>>>()?;
+    let mut converted: Vec<Option<ConvertedVideo>> = videos.iter().zip(&fingerprints)
+        .map(|(video, fingerprint)| build.unchanged(&mod_identifier, video, fingerprint.as_ref()?, output_dir))
+        .collect();
+    let mut changed = vec![];
+    for (index, (video, converted)) in videos.iter().zip(&converted).enumerate() {
+        if converted.is_some() {
+            println!("\n\"{}\" has not changed since it was last converted, skipping it", video.name);
+            on_progress(ProgressEvent { video: Some(index), ..ProgressEvent::of_mod(Stage::Done) });
+        } else {
+            changed.push((index, video));
+        }
+    }
+    let mut fresh = convert::convert_videos(&changed, &mod_identifier, options, &mode, cancel, &mut on_progress)?.into_iter();
+    for ((video, fingerprint), converted) in videos.iter().zip(fingerprints).zip(&mut converted) {
+        if converted.is_none() {
+            let video_result = fresh.next().expect("Every changed video was converted");
+            match fingerprint {
+                Some(fingerprint) => build.insert(&mod_identifier, video, fingerprint, &video_result, output_dir),
+                None => build.forget(&mod_identifier, video),
+            }
+            *converted = Some(video_result);
+        }
+    }
 
-        let (grid_amount, last_stop_time, audio_name) = convert::convert_video(video_path, &elongated_mod_identifier, &elongated_video_identifier, size, keep_aspect_ratio, &mode, video_framerate, &mut checkpoint_reached, has_nvenc, high_quality)?;
+    for (index, (video, ConvertedVideo { grid_amount, last_stop_time, audio_name, .. })) in videos.iter().zip(converted.into_iter().flatten()).enumerate() {
+        on_progress(ProgressEvent { video: Some(index), ..ProgressEvent::of_mod(Stage::WritingMeshes) });
+        let (video_name, video_identifier, video_framerate) = (&video.name, &video.identifier, video.framerate);
         if !write_drivein_esp {
             write_drivein_esp = grid_amount <= 8;
         }
 
         if generate_script {
-            script_video_data.push((elongated_video_identifier.clone(), video_name.clone(), audio_name.clone(), grid_amount <= 8));
+            script_video_data.push((video_identifier.clone(), video_name.clone(), audio_name.clone(), grid_amount <= 8));
         } else {
-            let mut esps = vec![&mut tv_esp_bytes];
-            if grid_amount <= 8 {
-                esps.push(&mut di_esp_bytes);
-            }
-            for bytes in &mut esps {
-                replace_all_strings_in_bytes(bytes, "AUTOCIDENT", &elongated_mod_identifier)?;
-                for key in ["AUTOVIDENT", "AUTOSIDENT", "AUTOPIDENT"] {
-                    replace_first_string_in_bytes(bytes, key, &elongated_video_identifier)?;
-                }
-                replace_all_strings_in_bytes(bytes, "AUTOTIDENT", &trailing_spaced_mod_identifier)?;
-                replace_all_strings_in_bytes(bytes, "AUTOMIDENT", &leading_spaced_mod_identifier)?;
-                replace_first_string_in_bytes(bytes, "ZAUTONIDEN", &trailing_spaced_video_identifier)?;
-                replace_first_string_in_bytes(bytes, "AUTOIDENTSOUND", &audio_name)?;
-            }
+            plugins.add_video(&PluginVideo { video_id: video_identifier, video_name, audio_name: &audio_name }, grid_amount <= 8)?;
         }
 
         let tv_mesh_bytes: &[u8] = if grid_amount <= 8 { include_bytes!("./assets/TV 8 Grids.nif") } else { include_bytes!("./assets/TV 24 Grids.nif") };
         let pr_mesh_bytes: &[u8] = if grid_amount <= 8 { include_bytes!("./assets/PR 8 Grids.nif") } else { include_bytes!("./assets/PR 24 Grids.nif") };
-        let mut mesh_bytes: Vec<(&str, &[u8])> = vec![("Television", tv_mesh_bytes), ("Projector", pr_mesh_bytes)];
+        let mut mesh_bytes: Vec<(Screen, &[u8])> = vec![(Screen::Television, tv_mesh_bytes), (Screen::Projector, pr_mesh_bytes)];
         if grid_amount <= 8 {
             let di_8_grid_bytes = include_bytes!("./assets/DI 8 Grids.nif");
-            mesh_bytes.push(("DriveIn", di_8_grid_bytes));
+            mesh_bytes.push((Screen::DriveIn, di_8_grid_bytes));
         }
-        for (key, bytes) in mesh_bytes {
-            let mut this_mesh_bytes = bytes.to_vec();
-            replace_all_strings_in_bytes(&mut this_mesh_bytes, "AUTOCIDENT", &elongated_video_identifier)?;
-            replace_all_strings_in_bytes(&mut this_mesh_bytes, "AUTOMIDENT", &elongated_mod_identifier)?;
-            for grid_nr in 1..25 {
-                let controller_float = match grid_nr.cmp(&grid_amount) {
-                    Ordering::Less => 25.6,
-                    Ordering::Equal => last_stop_time,
-                    Ordering::Greater => 0f32
-                };
-                let textkey_float = if controller_float == 0f32 || video_framerate == 10 {
-                    controller_float
-                } else {
-                    controller_float / video_framerate as f32 * 10f32
-                };
-                find_and_replace_float(&mut this_mesh_bytes, 121200f32 + grid_nr as f32, textkey_float);
-                find_and_replace_float(&mut this_mesh_bytes, 141400f32 + grid_nr as f32, controller_float);
-            }
-            find_and_replace_float(&mut this_mesh_bytes, 1313f32, (video_framerate as f32)/10f32);
-            let nif_path = format!("output/meshes/Videos/{key}/{elongated_mod_identifier}");
-            let nif_path = Path::new(&nif_path);
-            fs::create_dir_all(nif_path).unwrap();
-            let mut file = File::create(nif_path.join(format!("{elongated_video_identifier}.nif"))).unwrap();
-            file.write_all(&this_mesh_bytes).unwrap();
+        for (screen, template) in mesh_bytes {
+            let mesh = mesh::video_mesh(template, &mod_identifier, video_identifier, video.grid_set(screen), video_framerate, grid_amount, last_stop_time);
+            let nif_path = options.output.output_dir.join(mesh_folder(screen, &mod_identifier));
+            fs::create_dir_all(&nif_path).at(&nif_path)?;
+            let nif_file = nif_path.join(format!("{video_identifier}.nif"));
+            fs::write(&nif_file, mesh).at(&nif_file)?;
         }
     }
-    if generate_script {
-        scriptwrite::generate_script(&mod_name, &elongated_mod_identifier, &script_video_data, script_info)?;
+    on_progress(ProgressEvent::of_mod(Stage::WritingPlugin));
+    let (plugin_name, drive_in_plugin_name) = if generate_script {
+        (scriptwrite::generate_script(&mod_name, &mod_identifier, &script_video_data, options.script_info.clone(), &options.output.output_dir)?, None)
     } else {
-        let mut esp_file = File::create(format!("output/VotW_{}.esp", mod_name)).unwrap();
-        esp_file.write_all(&tv_esp_bytes).unwrap();
+        let esp_name = mod_name.replace(['<', '>', ':', '"', '/', '\\', '|', '?', '*'], "_");
+        let esp_path = options.output.output_dir.join(format!("VotW_{}.esp", esp_name));
+        fs::write(&esp_path, plugins.tv.to_bytes()).at(&esp_path)?;
+        let drive_in_plugin_name = format!("VotW_{}_DriveIn.esp", esp_name);
         if write_drivein_esp {
-            let mut esp_file = File::create(format!("output/VotW_{}_DriveIn.esp", mod_name)).unwrap();
-            esp_file.write_all(&di_esp_bytes).unwrap();
+            let esp_path = options.output.output_dir.join(&drive_in_plugin_name);
+            fs::write(&esp_path, plugins.drive_in.to_bytes()).at(&esp_path)?;
         }
+        (format!("VotW_{}.esp", esp_name), Some(drive_in_plugin_name))
+    };
+    if options.output.archives {
+        println!("\nPacking archives ...");
+        on_progress(ProgressEvent::of_mod(Stage::PackingArchives));
+        let moved = write_archives(output_dir, &options.output.texture_root(options.size), &plugin_name, &mod_identifier)?;
+        build.packed(&moved, output_dir);
+    }
+    if options.output.incremental || BuildManifest::exists(output_dir) {
+        build.save(output_dir)?;
+    }
+    if options.output.fomod {
+        println!("\nWriting installer ...");
+        on_progress(ProgressEvent::of_mod(Stage::WritingInstaller));
+        fomod::write_installer(output_dir, &mod_name, &mod_identifier, &plugin_name, drive_in_plugin_name.as_deref(), &videos, options.layout)?;
     }
 
     println!("\nFinished!");
     Ok(())
 }
 
-pub fn count_esp_placeholders(esp: PathBuf) -> u32 {
-    let mut bytes = vec![];
-    File::open(esp).unwrap().read_to_end(&mut bytes).unwrap();
-    count_strings_in_bytes(&bytes, "AUTOVIDENT")
-}
\ No newline at end of file
+/// Moves the loose files of the mod into the two archives Fallout 4 loads for the plugin: `<plugin> - Main.ba2` and `<plugin> - Textures.ba2`.
+/// The textures archive is placed in the texture root, next to the textures it replaces.
+/// Returns every file that was moved, with the archive it went into and its name in there
+fn write_archives(output_dir: &Path, texture_root: &Path, plugin_name: &str, mod_identifier: &str) -> Result<Vec<(PathBuf, PathBuf, String)>> {
+    let plugin_stem = Path::new(plugin_name).file_stem().unwrap_or_default().to_string_lossy();
+    let mut main_folders = vec![sound_folder(mod_identifier)];
+    main_folders.extend(Screen::ALL.map(|screen| mesh_folder(screen, mod_identifier)));
+    let texture_folders = vec![texture_folder(mod_identifier)];
+
+    let mut moved = vec![];
+    for (kind, suffix, root, folders) in [(ArchiveKind::General, "Main", output_dir, main_folders), (ArchiveKind::Textures, "Textures", texture_root, texture_folders)] {
+        let mut files = vec![];
+        for folder in folders.iter().map(|folder| root.join(folder)) {
+            loose_files(&folder, &mut files).at(&folder)?;
+        }
+        if files.is_empty() {
+            continue;
+        }
+        let entries: Vec<(String, PathBuf)> = files.iter().map(|file| {
+            let name = file.strip_prefix(root).unwrap_or(file).to_string_lossy().replace('/', "\\");
+            (name, file.clone())
+        }).collect();
+        let archive = root.join(format!("{plugin_stem} - {suffix}.ba2"));
+        ba2::pack(&archive, kind, &entries)?;
+
+        for (name, file) in entries {
+            fs::remove_file(&file).at(&file)?;
+            // Folders are only removed once they are empty
+            for folder in file.ancestors().skip(1).take_while(|folder| *folder != root) {
+                if fs::remove_dir(folder).is_err() {
+                    break;
+                }
+            }
+            moved.push((file, archive.clone(), name));
+        }
+    }
+    Ok(moved)
+}
+
+/// All files below a folder, which may not exist
+fn loose_files(folder: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    if !folder.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(folder)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            loose_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The TV and DriveIn esps the videos of a job are added to, read from the given esps or made from scratch
+struct Plugins<'a> {
+    options: &'a JobOptions,
+    mod_identifier: &'a str,
+    mod_name: &'a str,
+    tv: Plugin,
+    drive_in: Plugin,
+}
+
+impl<'a> Plugins<'a> {
+    fn load(options: &'a JobOptions, mod_identifier: &'a str, mod_name: &'a str) -> Result<Self> {
+        let tv = match &options.input_esp {
+            Some(input_esp) => read_esp(input_esp, "esp", records::find_tv_lists)?,
+            None => records::new_tv_plugin(mod_identifier, mod_name)
+        };
+        let drive_in = match &options.input_esp_drive_in {
+            Some(input_esp) => read_esp(input_esp, "DriveIn esp", records::find_drivein_lists)?,
+            None => records::new_drivein_plugin(mod_identifier, mod_name)
+        };
+        Ok(Plugins { options, mod_identifier, mod_name, tv, drive_in })
+    }
+
+    /// Adds the records of a video, to the DriveIn esp as well if it plays there
+    fn add_video(&mut self, video: &PluginVideo, drive_in: bool) -> Result<()> {
+        records::add_tv_video(&mut self.tv, self.mod_identifier, self.mod_name, video)
+            .map_err(|message| AutovideoError::InvalidEsp { path: self.options.input_esp.clone(), message })?;
+        if drive_in {
+            records::add_drivein_video(&mut self.drive_in, self.mod_identifier, self.mod_name, video)
+                .map_err(|message| AutovideoError::InvalidEsp { path: self.options.input_esp_drive_in.clone(), message })?;
+        }
+        Ok(())
+    }
+}
+
+fn read_esp<T>(path: &Path, description: &str, find_lists: fn(&Plugin) -> Result<T, String>) -> Result<Plugin> {
+    let invalid = |message: String| AutovideoError::InvalidEsp { path: Some(path.to_path_buf()), message };
+    if !(path.is_file() && path.extension().is_some_and(|e| e.eq_ignore_ascii_case("esp"))) {
+        return Err(invalid(format!("Given {description} file does not exist")));
+    }
+    let bytes = fs::read(path).at(path)?;
+    let mut plugin = Plugin::parse(&bytes).map_err(invalid)?;
+    records::remove_placeholders(&mut plugin);
+    find_lists(&plugin).map_err(|e| invalid(format!("Not a VotW {description}: {e}")))?;
+    Ok(plugin)
+}
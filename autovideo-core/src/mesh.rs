@@ -0,0 +1,379 @@
+use std::cmp::Ordering;
+use crate::grid::GridGeometry;
+use crate::job::GridSet;
+use crate::nif::{BlockData, ControlledBlock, ControllerData, Nif};
+
+/// Frames per row and column of the grids the templates were made for, their screens show 1/16 of the texture each way
+const TEMPLATE_COLUMNS: f32 = 16.0;
+/// Controlled variables of the shader controllers in the sequences, moving the texture horizontally and vertically
+const OFFSET_U: &str = "6";
+const OFFSET_V: &str = "8";
+
+/// Fills in a bundled mesh template for a video: its grid textures, its sound and the length of each grid.
+/// Every grid plays as its own `StageNN` controller sequence, grids the video does not use end immediately.
+/// Templates with fewer grids than the video get the missing ones added
+pub fn video_mesh(
+    template: &[u8],
+    mod_identifier: &str,
+    video_identifier: &str,
+    grids: &GridSet,
+    framerate: u32,
+    grid_amount: usize,
+    last_stop_time: f32
+) -> Vec<u8> {
+    let mut nif = Nif::parse(template).expect("Bundled mesh templates are valid");
+    add_grids(&mut nif, grid_amount).expect("Bundled mesh templates have complete grids to copy");
+    let geometry = grids.geometry;
+    set_geometry(&mut nif, geometry);
+    let frequency = framerate as f32 / 10.0;
+    // The templates always run at 10 frames per second
+    let grid_length = geometry.frames_per_grid() as f32 / 10.0;
+
+    let mut text_keys = vec![];
+    for index in 0..nif.blocks.len() {
+        let grid = match &nif.blocks[index].data {
+            BlockData::ControllerSequence(sequence) => nif.string(sequence.name).and_then(|name| name.strip_prefix("Stage")).and_then(|n| n.parse::<usize>().ok()),
+            _ => None
+        };
+        match &mut nif.blocks[index].data {
+            BlockData::Controller(controller) if matches!(controller.data, ControllerData::Manager { .. }) => controller.frequency = frequency,
+            BlockData::ControllerSequence(sequence) => {
+                sequence.frequency = frequency;
+                if let Some(grid) = grid {
+                    let stop_time = match grid.cmp(&grid_amount) {
+                        Ordering::Less => grid_length,
+                        Ordering::Equal => last_stop_time,
+                        Ordering::Greater => 0.0
+                    };
+                    sequence.stop_time = stop_time;
+                    text_keys.extend(sequence.text_keys.map(|block| (block, stop_time)));
+                }
+            }
+            BlockData::EffectShaderProperty(shader) => {
+                let file_name = shader.source_texture.rsplit('\\').next().unwrap_or_default();
+                shader.source_texture = match &grids.folder {
+                    Some(folder) => format!("Textures\\Videos\\{mod_identifier}\\{video_identifier}\\{folder}\\{file_name}"),
+                    None => format!("Textures\\Videos\\{mod_identifier}\\{video_identifier}\\{file_name}"),
+                };
+            }
+            _ => {}
+        }
+    }
+
+    // Text keys are placed on the timeline before the sequence frequency applies
+    for (block, stop_time) in text_keys {
+        let end_time = if framerate == 10 { stop_time } else { stop_time / framerate as f32 * 10.0 };
+        let Some(BlockData::TextKeyExtraData(text_keys)) = nif.blocks.get_mut(block as usize).map(|b| &mut b.data) else {
+            continue;
+        };
+        for key in &mut text_keys.keys {
+            match nif.strings.get_mut(key.value.unwrap_or(u32::MAX) as usize) {
+                Some(value) if value.starts_with("SoundPlay.") => *value = format!("SoundPlay.VotW_{mod_identifier}{video_identifier}Sound"),
+                // Keys at the start of the stage, like the bare SoundPlay of the DriveIn mesh
+                Some(value) if value == "start" || value == "SoundPlay" => {}
+                _ => key.time = end_time
+            }
+        }
+    }
+    nif.to_bytes()
+}
+
+/// The blocks that show one grid: a screen node with its shape and shader, the controller that makes it visible,
+/// the two controllers that move the texture from frame to frame, and the sequence that plays them
+struct Screen {
+    node: u32,
+    shape: u32,
+    shader: u32,
+    alpha: Option<u32>,
+    visibility: u32,
+    visibility_blend: u32,
+    offset_u: u32,
+    offset_v: u32,
+    offset_u_blend: u32,
+    offset_v_blend: u32,
+    sequence: u32,
+    text_keys: u32,
+}
+
+impl Screen {
+    fn find(nif: &Nif, grid: usize) -> Option<Screen> {
+        let node = nif.find_block(&format!("Screen{grid:02}"))?;
+        let sequence = nif.find_block(&format!("Stage{grid:02}"))?;
+        let BlockData::Node(screen) = block_data(nif, node)? else { return None };
+        let shape = (*screen.children.first()?)?;
+        let BlockData::TriShape(shape_data) = block_data(nif, shape)? else { return None };
+        let BlockData::EffectShaderProperty(shader) = block_data(nif, shape_data.shader_property?)? else { return None };
+        let BlockData::ControllerSequence(stage) = block_data(nif, sequence)? else { return None };
+        let visibility = screen.object.controller?;
+        let offset_u = shader.controller?;
+        let offset_v = controller(nif, offset_u)?.0?;
+        Some(Screen {
+            node,
+            shape,
+            shader: shape_data.shader_property?,
+            alpha: shape_data.alpha_property,
+            visibility,
+            visibility_blend: controller(nif, visibility)?.1?,
+            offset_u,
+            offset_v,
+            offset_u_blend: controller(nif, offset_u)?.1?,
+            offset_v_blend: controller(nif, offset_v)?.1?,
+            sequence,
+            text_keys: stage.text_keys?,
+        })
+    }
+
+    /// Appends a copy of all blocks of this screen as the screen of the given grid
+    fn copy(&self, nif: &mut Nif, grid: usize) -> Screen {
+        let mut push = |block: u32| {
+            let copy = nif.blocks[block as usize].clone();
+            nif.blocks.push(copy);
+            nif.blocks.len() as u32 - 1
+        };
+        let copy = Screen {
+            node: push(self.node),
+            shape: push(self.shape),
+            shader: push(self.shader),
+            alpha: self.alpha.map(&mut push),
+            visibility: push(self.visibility),
+            visibility_blend: push(self.visibility_blend),
+            offset_u: push(self.offset_u),
+            offset_v: push(self.offset_v),
+            offset_u_blend: push(self.offset_u_blend),
+            offset_v_blend: push(self.offset_v_blend),
+            sequence: push(self.sequence),
+            text_keys: push(self.text_keys),
+        };
+
+        let screen_name = nif.add_string(&format!("Screen{grid:02}"));
+        let shape_name = nif.add_string(&format!("Screen{grid:02}:0"));
+        let stage_name = nif.add_string(&format!("Stage{grid:02}"));
+        let blocks = &mut nif.blocks;
+        if let BlockData::Node(node) = &mut blocks[copy.node as usize].data {
+            node.object.name = Some(screen_name);
+            node.object.controller = Some(copy.visibility);
+            node.children = vec![Some(copy.shape)];
+        }
+        if let BlockData::TriShape(shape) = &mut blocks[copy.shape as usize].data {
+            shape.object.name = Some(shape_name);
+            shape.shader_property = Some(copy.shader);
+            shape.alpha_property = copy.alpha;
+        }
+        if let BlockData::EffectShaderProperty(shader) = &mut blocks[copy.shader as usize].data {
+            shader.controller = Some(copy.offset_u);
+            let folder = shader.source_texture.rsplit_once('\\').map_or("", |(folder, _)| folder);
+            shader.source_texture = format!("{folder}\\Grid{grid:02}.dds");
+        }
+        for (controller, target, next, blend) in [
+            (copy.visibility, copy.node, None, copy.visibility_blend),
+            (copy.offset_u, copy.shader, Some(copy.offset_v), copy.offset_u_blend),
+            (copy.offset_v, copy.shader, None, copy.offset_v_blend),
+        ] {
+            if let BlockData::Controller(controller) = &mut blocks[controller as usize].data {
+                controller.target = Some(target);
+                controller.next_controller = next;
+                if let ControllerData::Interpolated { interpolator, .. } = &mut controller.data {
+                    *interpolator = Some(blend);
+                }
+            }
+        }
+        if let BlockData::ControllerSequence(sequence) = &mut blocks[copy.sequence as usize].data {
+            sequence.name = Some(stage_name);
+            sequence.text_keys = Some(copy.text_keys);
+        }
+        copy
+    }
+}
+
+/// Adds grids to a template until it has `grid_amount`, copying the blocks of its last grid for every new one.
+/// All sequences are then rebuilt to show their own screen and hide every other one, like the sequences of the template do
+fn add_grids(nif: &mut Nif, grid_amount: usize) -> Option<()> {
+    let mut screens: Vec<Screen> = (1..).map_while(|grid| Screen::find(nif, grid)).collect();
+    let template_grids = screens.len();
+    if grid_amount <= template_grids {
+        return Some(());
+    }
+    let (visibility, offset_u, offset_v) = {
+        let template = screens.last()?;
+        (template.visibility, template.offset_u, template.offset_v)
+    };
+    let BlockData::ControllerSequence(sequence) = block_data(nif, screens.last()?.sequence)? else { return None };
+    let (own_blocks, other_blocks): (Vec<ControlledBlock>, Vec<ControlledBlock>) = sequence.controlled_blocks.iter().cloned()
+        .partition(|block| [Some(visibility), Some(offset_u), Some(offset_v)].contains(&block.controller));
+    let hidden_block = other_blocks.first()?.clone();
+
+    for grid in template_grids + 1..=grid_amount {
+        let screen = screens[template_grids - 1].copy(nif, grid);
+        screens.push(screen);
+    }
+    let new_screens = &screens[template_grids..];
+    for block in &mut nif.blocks {
+        match &mut block.data {
+            BlockData::Node(node) if node.children.contains(&Some(screens[0].node)) => {
+                node.children.extend(new_screens.iter().map(|screen| Some(screen.node)));
+            }
+            BlockData::ObjectPalette(palette) => {
+                for (index, screen) in new_screens.iter().enumerate() {
+                    let grid = template_grids + index + 1;
+                    palette.objects.push((format!("Screen{grid:02}"), Some(screen.node)));
+                    palette.objects.push((format!("Screen{grid:02}:0"), Some(screen.shape)));
+                }
+            }
+            BlockData::Controller(controller) => match &mut controller.data {
+                ControllerData::Manager { sequences, .. } => sequences.extend(new_screens.iter().map(|screen| Some(screen.sequence))),
+                // The templates list one empty target per screen
+                ControllerData::MultiTargetTransform { extra_targets } => extra_targets.resize(grid_amount, None),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    let names: Vec<(Option<u32>, Option<u32>)> = screens.iter().map(|screen| {
+        let name = |index: u32| match block_data(nif, index) {
+            Some(BlockData::Node(node)) => node.object.name,
+            Some(BlockData::TriShape(shape)) => shape.object.name,
+            _ => None
+        };
+        (name(screen.node), name(screen.shape))
+    }).collect();
+    for (index, screen) in screens.iter().enumerate() {
+        let mut controlled_blocks: Vec<ControlledBlock> = own_blocks.iter().map(|block| {
+            let (controller, node_name) = match block.controller {
+                Some(controller) if controller == visibility => (screen.visibility, names[index].0),
+                Some(controller) if controller == offset_u => (screen.offset_u, names[index].1),
+                _ => (screen.offset_v, names[index].1)
+            };
+            ControlledBlock { controller: Some(controller), node_name, ..block.clone() }
+        }).collect();
+        for (other_index, other) in screens.iter().enumerate().filter(|(other_index, _)| *other_index != index) {
+            controlled_blocks.push(ControlledBlock { controller: Some(other.visibility), node_name: names[other_index].0, ..hidden_block.clone() });
+        }
+        if let BlockData::ControllerSequence(sequence) = &mut nif.blocks[screen.sequence as usize].data {
+            sequence.controlled_blocks = controlled_blocks;
+        }
+    }
+
+    // The last sequence starts over at the first one and resets, the template's last sequence now continues to the next grid
+    let reset = nif.add_string("reset");
+    for grid in template_grids..=grid_amount {
+        let next_stage = nif.add_string(&format!("Stage{:02}", if grid == grid_amount { 1 } else { grid + 1 }));
+        let Some(BlockData::TextKeyExtraData(text_keys)) = nif.blocks.get_mut(screens[grid - 1].text_keys as usize).map(|b| &mut b.data) else {
+            continue;
+        };
+        text_keys.keys.retain(|key| grid == grid_amount || key.value != Some(reset));
+        for key in &mut text_keys.keys {
+            if nif.strings.get(key.value.unwrap_or(u32::MAX) as usize).is_some_and(|value| value.starts_with("Stage")) {
+                key.value = Some(next_stage);
+            }
+        }
+    }
+    Some(())
+}
+
+/// Moves the texture of every screen through the frames of the grids: a column per frame and a row every `columns` frames.
+/// The screens are scaled to show a single frame, and their controllers run for as long as a grid plays
+fn set_geometry(nif: &mut Nif, geometry: GridGeometry) {
+    let GridGeometry { columns, rows, frame_height, height, .. } = geometry;
+    let grid_length = geometry.frames_per_grid() as f32 / 10.0;
+    let mut offsets = vec![];
+    for block in &nif.blocks {
+        let BlockData::ControllerSequence(sequence) = &block.data else { continue };
+        for controlled_block in &sequence.controlled_blocks {
+            let Some(BlockData::FloatInterpolator(interpolator)) = controlled_block.interpolator.and_then(|index| block_data(nif, index)) else { continue };
+            if let (Some(data), Some(variable @ (OFFSET_U | OFFSET_V))) = (interpolator.data, nif.string(controlled_block.controller_id)) {
+                offsets.push((data, variable == OFFSET_U));
+            }
+        }
+    }
+    for (data, horizontal) in offsets {
+        let Some(BlockData::FloatData(data)) = nif.blocks.get_mut(data as usize).map(|b| &mut b.data) else { continue };
+        data.keys = if horizontal {
+            (0..geometry.frames_per_grid()).map(|frame| (frame as f32 / 10.0, (frame as u32 % columns) as f32 / columns as f32)).collect()
+        } else {
+            (0..rows).map(|row| ((row * columns) as f32 / 10.0, (row * frame_height) as f32 / height as f32)).collect()
+        };
+    }
+    for block in &mut nif.blocks {
+        match &mut block.data {
+            BlockData::EffectShaderProperty(shader) => {
+                shader.uv_scale = [TEMPLATE_COLUMNS / columns as f32, TEMPLATE_COLUMNS * frame_height as f32 / height as f32];
+            }
+            // The visibility and texture offset controllers of the screens
+            BlockData::Controller(controller) if matches!(controller.data, ControllerData::Interpolated { .. }) => {
+                controller.start_time = 0.0;
+                controller.stop_time = grid_length;
+            }
+            _ => {}
+        }
+    }
+}
+
+fn block_data(nif: &Nif, index: u32) -> Option<&BlockData> {
+    nif.blocks.get(index as usize).map(|block| &block.data)
+}
+
+/// Next controller and interpolator of a single interpolator controller
+fn controller(nif: &Nif, index: u32) -> Option<(Option<u32>, Option<u32>)> {
+    match block_data(nif, index)? {
+        BlockData::Controller(controller) => match controller.data {
+            ControllerData::Interpolated { interpolator, .. } => Some((controller.next_controller, interpolator)),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::GridLayout;
+    use crate::nif;
+
+    const TEMPLATES: [(&str, &[u8]); 3] = [
+        ("TV 8 Grids", include_bytes!("./assets/TV 8 Grids.nif")),
+        ("TV 24 Grids", include_bytes!("./assets/TV 24 Grids.nif")),
+        ("DI 8 Grids", include_bytes!("./assets/DI 8 Grids.nif")),
+    ];
+
+    fn mesh(template: &[u8], layout: GridLayout, grid_amount: usize) -> Nif {
+        let grids = GridSet { geometry: layout.geometry(512, false), folder: None, screens: vec![] };
+        Nif::parse(&video_mesh(template, "Mod", "Video", &grids, 10, grid_amount, 1.0)).unwrap()
+    }
+
+    #[test]
+    fn default_layout_keeps_the_template_timings() {
+        for (name, template) in TEMPLATES {
+            let nif = mesh(template, GridLayout::default(), 8);
+            for block in &nif.blocks {
+                if let BlockData::Controller(controller @ nif::Controller { data: ControllerData::Interpolated { .. }, .. }) = &block.data {
+                    assert_eq!(controller.stop_time, 25.6, "{name}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn controllers_run_for_a_grid_of_the_layout() {
+        let layout = GridLayout { columns: 12, rows: 12 };
+        for (name, template) in TEMPLATES {
+            for grid_amount in [8, 30] {
+                let nif = mesh(template, layout, grid_amount);
+                let bytes = nif.to_bytes();
+                assert!(!bytes.windows(4).any(|float| float == 25.6f32.to_le_bytes()), "{name} with {grid_amount} grids has 16x16 timings left");
+                let mut controllers = 0;
+                for block in &nif.blocks {
+                    match &block.data {
+                        BlockData::Controller(controller) if matches!(controller.data, ControllerData::Interpolated { .. }) => {
+                            assert_eq!((controller.start_time, controller.stop_time), (0.0, 14.4), "{name}");
+                            controllers += 1;
+                        }
+                        BlockData::FloatData(data) => assert!(data.keys.iter().all(|(time, _)| *time < 14.4), "{name}"),
+                        _ => {}
+                    }
+                }
+                // A visibility and two texture offset controllers per screen
+                assert_eq!(controllers, 3 * grid_amount.max(if name.contains("24") { 24 } else { 8 }), "{name}");
+            }
+        }
+    }
+}
@@ -0,0 +1,693 @@
+const HEADER_STRING: &[u8] = b"Gamebryo File Format, Version 20.2.0.7\n";
+const VERSION: u32 = 0x14020007;
+const FALLOUT4_BS_VERSION: u32 = 130;
+const KEY_LINEAR: u8 = 1;
+const KEY_CONSTANT: u8 = 5;
+const NODE_TYPES: [&str; 3] = ["NiNode", "BSFadeNode", "BSOrderedNode"];
+const SINGLE_INTERPOLATOR_CONTROLLERS: [&str; 5] = [
+    "NiVisController",
+    "NiTextureTransformController",
+    "BSEffectShaderPropertyFloatController",
+    "BSEffectShaderPropertyColorController",
+    "BSLightingShaderPropertyFloatController",
+];
+
+/// A Fallout 4 NIF file. Blocks the generator edits are parsed into their fields, all others are kept as raw bytes
+pub struct Nif {
+    pub user_version: u32,
+    pub bs_version: u32,
+    pub author: String,
+    pub process_script: String,
+    pub export_script: String,
+    pub max_filepath: String,
+    pub strings: Vec<String>,
+    pub groups: Vec<u32>,
+    pub blocks: Vec<Block>,
+    pub roots: Vec<Option<u32>>,
+}
+
+#[derive(Clone)]
+pub struct Block {
+    pub type_name: String,
+    pub data: BlockData,
+}
+
+#[derive(Clone)]
+pub enum BlockData {
+    Node(Node),
+    TriShape(TriShape),
+    ObjectPalette(ObjectPalette),
+    Controller(Controller),
+    ControllerSequence(ControllerSequence),
+    TextKeyExtraData(TextKeyExtraData),
+    EffectShaderProperty(EffectShaderProperty),
+    FloatInterpolator(FloatInterpolator),
+    FloatData(FloatData),
+    Raw(Vec<u8>),
+}
+
+/// The NiAVObject fields nodes and shapes start with
+#[derive(Clone)]
+pub struct AvObject {
+    pub name: Option<u32>,
+    pub extra_data: Vec<Option<u32>>,
+    pub controller: Option<u32>,
+    pub flags: u32,
+    pub translation: [f32; 3],
+    pub rotation: [f32; 9],
+    pub scale: f32,
+    pub collision_object: Option<u32>,
+}
+
+/// A NiNode or one of its subtypes, like the BSOrderedNode holding the screens
+#[derive(Clone)]
+pub struct Node {
+    pub object: AvObject,
+    pub children: Vec<Option<u32>>,
+    pub rest: Vec<u8>,
+}
+
+/// A BSTriShape up to its properties, the vertex and triangle data is kept as is
+#[derive(Clone)]
+pub struct TriShape {
+    pub object: AvObject,
+    pub bounding_sphere: [f32; 4],
+    pub skin: Option<u32>,
+    pub shader_property: Option<u32>,
+    pub alpha_property: Option<u32>,
+    pub rest: Vec<u8>,
+}
+
+/// Names of the objects the controller sequences animate, with the blocks they belong to
+#[derive(Clone)]
+pub struct ObjectPalette {
+    pub scene: Option<u32>,
+    pub objects: Vec<(String, Option<u32>)>,
+}
+
+/// The NiTimeController fields every controller starts with, followed by the fields of the specific controller type
+#[derive(Clone)]
+pub struct Controller {
+    pub next_controller: Option<u32>,
+    pub flags: u16,
+    pub frequency: f32,
+    pub phase: f32,
+    pub start_time: f32,
+    pub stop_time: f32,
+    pub target: Option<u32>,
+    pub data: ControllerData,
+}
+
+#[derive(Clone)]
+pub enum ControllerData {
+    /// Controllers driven by a single interpolator, like NiVisController and BSEffectShaderPropertyFloatController
+    Interpolated { interpolator: Option<u32>, rest: Vec<u8> },
+    MultiTargetTransform { extra_targets: Vec<Option<u32>> },
+    Manager { cumulative: u8, sequences: Vec<Option<u32>>, object_palette: Option<u32> },
+    Raw(Vec<u8>),
+}
+
+#[derive(Clone)]
+pub struct ControlledBlock {
+    pub interpolator: Option<u32>,
+    pub controller: Option<u32>,
+    pub priority: u8,
+    pub node_name: Option<u32>,
+    pub property_type: Option<u32>,
+    pub controller_type: Option<u32>,
+    pub controller_id: Option<u32>,
+    pub interpolator_id: Option<u32>,
+}
+
+#[derive(Clone)]
+pub struct ControllerSequence {
+    pub name: Option<u32>,
+    pub array_grow_by: u32,
+    pub controlled_blocks: Vec<ControlledBlock>,
+    pub weight: f32,
+    pub text_keys: Option<u32>,
+    pub cycle_type: u32,
+    pub frequency: f32,
+    pub start_time: f32,
+    pub stop_time: f32,
+    pub manager: Option<u32>,
+    pub accum_root_name: Option<u32>,
+    pub anim_note_arrays: Vec<Option<u32>>,
+}
+
+#[derive(Clone)]
+pub struct TextKey {
+    pub time: f32,
+    pub value: Option<u32>,
+}
+
+#[derive(Clone)]
+pub struct TextKeyExtraData {
+    pub name: Option<u32>,
+    pub keys: Vec<TextKey>,
+}
+
+/// Shader of the screens, the source texture is the grid shown on them
+#[derive(Clone)]
+pub struct EffectShaderProperty {
+    pub name: Option<u32>,
+    pub extra_data: Vec<Option<u32>>,
+    pub controller: Option<u32>,
+    pub shader_flags: [u32; 2],
+    pub uv_offset: [f32; 2],
+    pub uv_scale: [f32; 2],
+    pub source_texture: String,
+    pub rest: Vec<u8>,
+}
+
+#[derive(Clone)]
+pub struct FloatInterpolator {
+    pub value: f32,
+    pub data: Option<u32>,
+}
+
+/// Keyframes of a float animation. Only linear and constant keys are parsed, which are a time and a value each
+#[derive(Clone)]
+pub struct FloatData {
+    pub key_type: u32,
+    pub keys: Vec<(f32, f32)>,
+}
+
+impl Nif {
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        if !data.starts_with(HEADER_STRING) {
+            return Err("File is not a Fallout 4 NIF".to_string());
+        }
+        let mut reader = Reader { data, position: HEADER_STRING.len() };
+        if reader.u32()? != VERSION || reader.u8()? != 1 {
+            return Err("Only little endian NIF version 20.2.0.7 is supported".to_string());
+        }
+        let user_version = reader.u32()?;
+        let block_amount = reader.u32()? as usize;
+        let bs_version = reader.u32()?;
+        if bs_version != FALLOUT4_BS_VERSION {
+            return Err(format!("Unsupported Bethesda stream version {bs_version}"));
+        }
+        let author = reader.short_string()?;
+        let process_script = reader.short_string()?;
+        let export_script = reader.short_string()?;
+        let max_filepath = reader.short_string()?;
+
+        let block_types = (0..reader.u16()?).map(|_| reader.sized_string()).collect::<Result<Vec<_>, _>>()?;
+        let type_indices = (0..block_amount).map(|_| reader.u16()).collect::<Result<Vec<_>, _>>()?;
+        let block_sizes = (0..block_amount).map(|_| reader.u32()).collect::<Result<Vec<_>, _>>()?;
+        let string_amount = reader.u32()?;
+        // Maximum string length, recalculated on write
+        reader.u32()?;
+        let strings = (0..string_amount).map(|_| reader.sized_string()).collect::<Result<Vec<_>, _>>()?;
+        let groups = (0..reader.u32()?).map(|_| reader.u32()).collect::<Result<Vec<_>, _>>()?;
+
+        let mut blocks = Vec::with_capacity(block_amount);
+        for (type_index, size) in type_indices.into_iter().zip(block_sizes) {
+            let type_name = block_types.get(type_index as usize).ok_or(format!("Block type {type_index} does not exist"))?.clone();
+            let data = reader.bytes(size as usize)?;
+            let data = BlockData::parse(&type_name, data).map_err(|e| format!("Invalid {type_name} block: {e}"))?;
+            blocks.push(Block { type_name, data });
+        }
+        let roots = (0..reader.u32()?).map(|_| reader.reference()).collect::<Result<Vec<_>, _>>()?;
+        Ok(Nif { user_version, bs_version, author, process_script, export_script, max_filepath, strings, groups, blocks, roots })
+    }
+
+    pub fn string(&self, index: Option<u32>) -> Option<&str> {
+        self.strings.get(index? as usize).map(String::as_str)
+    }
+
+    /// Index of the string in the string table, adding it when it is not in there yet
+    pub fn add_string(&mut self, value: &str) -> u32 {
+        match self.strings.iter().position(|s| s == value) {
+            Some(index) => index as u32,
+            None => {
+                self.strings.push(value.to_string());
+                self.strings.len() as u32 - 1
+            }
+        }
+    }
+
+    /// The first node, shape or controller sequence with the given name
+    pub fn find_block(&self, name: &str) -> Option<u32> {
+        self.blocks.iter().position(|block| {
+            let index = match &block.data {
+                BlockData::Node(node) => node.object.name,
+                BlockData::TriShape(shape) => shape.object.name,
+                BlockData::ControllerSequence(sequence) => sequence.name,
+                _ => None
+            };
+            self.string(index) == Some(name)
+        }).map(|index| index as u32)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut block_types: Vec<&str> = vec![];
+        let mut type_indices = Vec::with_capacity(self.blocks.len());
+        for block in &self.blocks {
+            let index = match block_types.iter().position(|t| *t == block.type_name) {
+                Some(index) => index,
+                None => {
+                    block_types.push(&block.type_name);
+                    block_types.len() - 1
+                }
+            };
+            type_indices.push(index as u16);
+        }
+        let blocks: Vec<Vec<u8>> = self.blocks.iter().map(|b| b.data.to_bytes()).collect();
+
+        let mut out = Writer(HEADER_STRING.to_vec());
+        out.u32(VERSION);
+        out.u8(1);
+        out.u32(self.user_version);
+        out.u32(blocks.len() as u32);
+        out.u32(self.bs_version);
+        for string in [&self.author, &self.process_script, &self.export_script, &self.max_filepath] {
+            out.short_string(string);
+        }
+        out.u16(block_types.len() as u16);
+        for block_type in block_types {
+            out.sized_string(block_type);
+        }
+        for index in type_indices {
+            out.u16(index);
+        }
+        for block in &blocks {
+            out.u32(block.len() as u32);
+        }
+        out.u32(self.strings.len() as u32);
+        out.u32(self.strings.iter().map(String::len).max().unwrap_or(0) as u32);
+        for string in &self.strings {
+            out.sized_string(string);
+        }
+        out.u32(self.groups.len() as u32);
+        for group in &self.groups {
+            out.u32(*group);
+        }
+        for block in blocks {
+            out.0.extend(block);
+        }
+        out.u32(self.roots.len() as u32);
+        for root in &self.roots {
+            out.reference(*root);
+        }
+        out.0
+    }
+}
+
+impl BlockData {
+    fn parse(type_name: &str, data: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader { data, position: 0 };
+        let block = match type_name {
+            _ if NODE_TYPES.contains(&type_name) => BlockData::Node(Node {
+                object: reader.av_object()?,
+                children: reader.references()?,
+                rest: reader.rest(),
+            }),
+            "BSTriShape" => BlockData::TriShape(TriShape {
+                object: reader.av_object()?,
+                bounding_sphere: reader.floats()?,
+                skin: reader.reference()?,
+                shader_property: reader.reference()?,
+                alpha_property: reader.reference()?,
+                rest: reader.rest(),
+            }),
+            "NiDefaultAVObjectPalette" => BlockData::ObjectPalette(ObjectPalette {
+                scene: reader.reference()?,
+                objects: (0..reader.u32()?).map(|_| Ok((reader.sized_string()?, reader.reference()?))).collect::<Result<_, String>>()?,
+            }),
+            "NiControllerSequence" => {
+                let name = reader.reference()?;
+                let controlled_block_amount = reader.u32()?;
+                let array_grow_by = reader.u32()?;
+                BlockData::ControllerSequence(ControllerSequence {
+                    name,
+                    array_grow_by,
+                    controlled_blocks: (0..controlled_block_amount).map(|_| Ok(ControlledBlock {
+                        interpolator: reader.reference()?,
+                        controller: reader.reference()?,
+                        priority: reader.u8()?,
+                        node_name: reader.reference()?,
+                        property_type: reader.reference()?,
+                        controller_type: reader.reference()?,
+                        controller_id: reader.reference()?,
+                        interpolator_id: reader.reference()?,
+                    })).collect::<Result<_, String>>()?,
+                    weight: reader.f32()?,
+                    text_keys: reader.reference()?,
+                    cycle_type: reader.u32()?,
+                    frequency: reader.f32()?,
+                    start_time: reader.f32()?,
+                    stop_time: reader.f32()?,
+                    manager: reader.reference()?,
+                    accum_root_name: reader.reference()?,
+                    anim_note_arrays: (0..reader.u16()?).map(|_| reader.reference()).collect::<Result<_, _>>()?,
+                })
+            }
+            "NiTextKeyExtraData" => BlockData::TextKeyExtraData(TextKeyExtraData {
+                name: reader.reference()?,
+                keys: (0..reader.u32()?).map(|_| Ok(TextKey { time: reader.f32()?, value: reader.reference()? })).collect::<Result<_, String>>()?,
+            }),
+            "BSEffectShaderProperty" => BlockData::EffectShaderProperty(EffectShaderProperty {
+                name: reader.reference()?,
+                extra_data: reader.references()?,
+                controller: reader.reference()?,
+                shader_flags: [reader.u32()?, reader.u32()?],
+                uv_offset: reader.floats()?,
+                uv_scale: reader.floats()?,
+                source_texture: reader.sized_string()?,
+                rest: reader.rest(),
+            }),
+            "NiFloatInterpolator" => BlockData::FloatInterpolator(FloatInterpolator {
+                value: reader.f32()?,
+                data: reader.reference()?,
+            }),
+            // The key type follows the amount of keys, and is left out when there are none
+            "NiFloatData" if matches!(data.get(4..8), Some([KEY_LINEAR | KEY_CONSTANT, 0, 0, 0])) => {
+                let key_amount = reader.u32()?;
+                BlockData::FloatData(FloatData {
+                    key_type: reader.u32()?,
+                    keys: (0..key_amount).map(|_| Ok((reader.f32()?, reader.f32()?))).collect::<Result<_, String>>()?,
+                })
+            }
+            _ if type_name == "NiControllerManager" || type_name.ends_with("Controller") => BlockData::Controller(Controller {
+                next_controller: reader.reference()?,
+                flags: reader.u16()?,
+                frequency: reader.f32()?,
+                phase: reader.f32()?,
+                start_time: reader.f32()?,
+                stop_time: reader.f32()?,
+                target: reader.reference()?,
+                data: match type_name {
+                    "NiControllerManager" => ControllerData::Manager {
+                        cumulative: reader.u8()?,
+                        sequences: reader.references()?,
+                        object_palette: reader.reference()?,
+                    },
+                    "NiMultiTargetTransformController" => ControllerData::MultiTargetTransform {
+                        extra_targets: (0..reader.u16()?).map(|_| reader.reference()).collect::<Result<_, _>>()?,
+                    },
+                    _ if SINGLE_INTERPOLATOR_CONTROLLERS.contains(&type_name) => ControllerData::Interpolated {
+                        interpolator: reader.reference()?,
+                        rest: reader.rest(),
+                    },
+                    _ => ControllerData::Raw(reader.rest()),
+                },
+            }),
+            _ => BlockData::Raw(reader.rest()),
+        };
+        if reader.position != data.len() {
+            return Err(format!("{} unexpected bytes at the end", data.len() - reader.position));
+        }
+        Ok(block)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Writer(vec![]);
+        match self {
+            BlockData::Node(node) => {
+                out.av_object(&node.object);
+                out.references(&node.children);
+                out.0.extend(&node.rest);
+            }
+            BlockData::TriShape(shape) => {
+                out.av_object(&shape.object);
+                shape.bounding_sphere.iter().for_each(|value| out.f32(*value));
+                out.reference(shape.skin);
+                out.reference(shape.shader_property);
+                out.reference(shape.alpha_property);
+                out.0.extend(&shape.rest);
+            }
+            BlockData::ObjectPalette(palette) => {
+                out.reference(palette.scene);
+                out.u32(palette.objects.len() as u32);
+                for (name, object) in &palette.objects {
+                    out.sized_string(name);
+                    out.reference(*object);
+                }
+            }
+            BlockData::Controller(controller) => {
+                out.reference(controller.next_controller);
+                out.u16(controller.flags);
+                out.f32(controller.frequency);
+                out.f32(controller.phase);
+                out.f32(controller.start_time);
+                out.f32(controller.stop_time);
+                out.reference(controller.target);
+                match &controller.data {
+                    ControllerData::Interpolated { interpolator, rest } => {
+                        out.reference(*interpolator);
+                        out.0.extend(rest);
+                    }
+                    ControllerData::MultiTargetTransform { extra_targets } => {
+                        out.u16(extra_targets.len() as u16);
+                        for target in extra_targets {
+                            out.reference(*target);
+                        }
+                    }
+                    ControllerData::Manager { cumulative, sequences, object_palette } => {
+                        out.u8(*cumulative);
+                        out.references(sequences);
+                        out.reference(*object_palette);
+                    }
+                    ControllerData::Raw(data) => out.0.extend(data),
+                }
+            }
+            BlockData::ControllerSequence(sequence) => {
+                out.reference(sequence.name);
+                out.u32(sequence.controlled_blocks.len() as u32);
+                out.u32(sequence.array_grow_by);
+                for block in &sequence.controlled_blocks {
+                    out.reference(block.interpolator);
+                    out.reference(block.controller);
+                    out.u8(block.priority);
+                    for string in [block.node_name, block.property_type, block.controller_type, block.controller_id, block.interpolator_id] {
+                        out.reference(string);
+                    }
+                }
+                out.f32(sequence.weight);
+                out.reference(sequence.text_keys);
+                out.u32(sequence.cycle_type);
+                out.f32(sequence.frequency);
+                out.f32(sequence.start_time);
+                out.f32(sequence.stop_time);
+                out.reference(sequence.manager);
+                out.reference(sequence.accum_root_name);
+                out.u16(sequence.anim_note_arrays.len() as u16);
+                for array in &sequence.anim_note_arrays {
+                    out.reference(*array);
+                }
+            }
+            BlockData::TextKeyExtraData(text_keys) => {
+                out.reference(text_keys.name);
+                out.u32(text_keys.keys.len() as u32);
+                for key in &text_keys.keys {
+                    out.f32(key.time);
+                    out.reference(key.value);
+                }
+            }
+            BlockData::EffectShaderProperty(shader) => {
+                out.reference(shader.name);
+                out.references(&shader.extra_data);
+                out.reference(shader.controller);
+                shader.shader_flags.iter().for_each(|flags| out.u32(*flags));
+                shader.uv_offset.iter().chain(&shader.uv_scale).for_each(|value| out.f32(*value));
+                out.sized_string(&shader.source_texture);
+                out.0.extend(&shader.rest);
+            }
+            BlockData::FloatInterpolator(interpolator) => {
+                out.f32(interpolator.value);
+                out.reference(interpolator.data);
+            }
+            BlockData::FloatData(data) => {
+                out.u32(data.keys.len() as u32);
+                if !data.keys.is_empty() {
+                    out.u32(data.key_type);
+                }
+                for (time, value) in &data.keys {
+                    out.f32(*time);
+                    out.f32(*value);
+                }
+            }
+            BlockData::Raw(data) => out.0.extend(data),
+        }
+        out.0
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let bytes = self.data.get(self.position..self.position + length).ok_or("Unexpected end of NIF data")?;
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn rest(&mut self) -> Vec<u8> {
+        let rest = self.data[self.position..].to_vec();
+        self.position = self.data.len();
+        rest
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn f32(&mut self) -> Result<f32, String> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+
+    /// A block reference or string table index, where -1 means none
+    fn reference(&mut self) -> Result<Option<u32>, String> {
+        let value = self.u32()?;
+        Ok((value != u32::MAX).then_some(value))
+    }
+
+    /// A u32 count followed by that many references
+    fn references(&mut self) -> Result<Vec<Option<u32>>, String> {
+        (0..self.u32()?).map(|_| self.reference()).collect()
+    }
+
+    fn av_object(&mut self) -> Result<AvObject, String> {
+        Ok(AvObject {
+            name: self.reference()?,
+            extra_data: self.references()?,
+            controller: self.reference()?,
+            flags: self.u32()?,
+            translation: self.floats()?,
+            rotation: self.floats()?,
+            scale: self.f32()?,
+            collision_object: self.reference()?,
+        })
+    }
+
+    fn floats<const N: usize>(&mut self) -> Result<[f32; N], String> {
+        let mut values = [0.0; N];
+        for value in &mut values {
+            *value = self.f32()?;
+        }
+        Ok(values)
+    }
+
+    fn sized_string(&mut self) -> Result<String, String> {
+        let length = self.u32()? as usize;
+        Self::string(self.bytes(length)?)
+    }
+
+    /// A string with a u8 length, including its null terminator
+    fn short_string(&mut self) -> Result<String, String> {
+        let length = self.u8()? as usize;
+        let bytes = self.bytes(length)?;
+        Self::string(bytes.strip_suffix(&[0]).unwrap_or(bytes))
+    }
+
+    fn string(bytes: &[u8]) -> Result<String, String> {
+        String::from_utf8(bytes.to_vec()).map_err(|_| "String is not valid UTF-8".to_string())
+    }
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend(value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend(value.to_le_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.0.extend(value.to_le_bytes());
+    }
+
+    fn reference(&mut self, value: Option<u32>) {
+        self.u32(value.unwrap_or(u32::MAX));
+    }
+
+    fn references(&mut self, values: &[Option<u32>]) {
+        self.u32(values.len() as u32);
+        for value in values {
+            self.reference(*value);
+        }
+    }
+
+    fn av_object(&mut self, object: &AvObject) {
+        self.reference(object.name);
+        self.references(&object.extra_data);
+        self.reference(object.controller);
+        self.u32(object.flags);
+        object.translation.iter().chain(&object.rotation).for_each(|value| self.f32(*value));
+        self.f32(object.scale);
+        self.reference(object.collision_object);
+    }
+
+    fn sized_string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.0.extend(value.as_bytes());
+    }
+
+    fn short_string(&mut self, value: &str) {
+        self.u8(value.len() as u8 + 1);
+        self.0.extend(value.as_bytes());
+        self.0.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPLATES: [(&str, &[u8]); 5] = [
+        ("TV 8 Grids", include_bytes!("./assets/TV 8 Grids.nif")),
+        ("TV 24 Grids", include_bytes!("./assets/TV 24 Grids.nif")),
+        ("PR 8 Grids", include_bytes!("./assets/PR 8 Grids.nif")),
+        ("PR 24 Grids", include_bytes!("./assets/PR 24 Grids.nif")),
+        ("DI 8 Grids", include_bytes!("./assets/DI 8 Grids.nif")),
+    ];
+
+    #[test]
+    fn templates_are_written_back_unchanged() {
+        for (name, bytes) in TEMPLATES {
+            let nif = Nif::parse(bytes).unwrap_or_else(|e| panic!("{name}: {e}"));
+            assert!(nif.to_bytes() == bytes, "{name} changed by parsing and writing it");
+        }
+    }
+
+    #[test]
+    fn added_strings_are_reused() {
+        let mut nif = Nif::parse(TEMPLATES[0].1).unwrap();
+        let amount = nif.strings.len();
+        let index = nif.add_string("Screen99");
+        assert_eq!((index as usize, nif.add_string("Screen99")), (amount, index));
+        assert_eq!(nif.string(Some(index)), Some("Screen99"));
+        let nif = Nif::parse(&nif.to_bytes()).unwrap();
+        assert_eq!(nif.string(Some(index)), Some("Screen99"));
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        assert!(Nif::parse(b"TES4").is_err());
+        assert!(Nif::parse(&TEMPLATES[0].1[..100]).is_err());
+    }
+}
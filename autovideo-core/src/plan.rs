@@ -0,0 +1,112 @@
+use std::path::PathBuf;
+use serde::Serialize;
+use crate::error::{AutovideoError, Result};
+use crate::job::{ConversionJob, Screen};
+use crate::probe::{probe, ProbeInfo};
+use crate::records::PluginVideo;
+use crate::utility::identifier;
+use crate::Plugins;
+
+/// What converting a job would make, worked out from probing its videos without writing anything
+#[derive(Clone, Debug, Serialize)]
+pub struct Plan {
+    pub videos: Vec<VideoPlan>,
+    /// Grid textures of all videos, before packing them into archives
+    pub texture_bytes: u64,
+    pub audio_bytes: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct VideoPlan {
+    pub name: String,
+    pub path: PathBuf,
+    /// Length of the input in seconds
+    pub input_duration: f64,
+    /// Average framerate of the input, its frames are dropped or repeated to get `framerate`
+    pub input_framerate: Option<f64>,
+    /// Seconds of the input that are converted
+    pub duration: f64,
+    pub framerate: u32,
+    /// The trim range leaves out part of the input
+    pub trimmed: bool,
+    /// Part of the input doesn't play everywhere, because of the trim range or a screen that shows fewer grids than the video has
+    pub truncated: bool,
+    pub frames: usize,
+    pub grids: usize,
+    pub screens: Vec<ScreenPlan>,
+    pub texture_bytes: u64,
+    /// Zero for videos without sound
+    pub audio_bytes: u64,
+}
+
+/// Whether a video plays on one of the screens
+#[derive(Clone, Debug, Serialize)]
+pub struct ScreenPlan {
+    pub screen: Screen,
+    /// Longest a video can be to play on the screen, none for screens that show any amount of grids
+    pub max_duration: Option<f64>,
+    /// The video has more grids than the screen shows, so it doesn't get a mesh for it
+    pub truncated: bool,
+}
+
+/// Probes the videos of a job with ffprobe and works out their grids and output size.
+/// Checks the names and esps like a conversion does, so a plan without errors only fails to convert if ffmpeg does
+pub fn plan_videos(job: &ConversionJob) -> Result<Plan> {
+    let videos = job.prepare()?;
+    let options = &job.options;
+    let mod_name = job.mod_name.trim().to_string();
+    let mod_identifier = identifier(&mod_name);
+    let generate_script = options.generate_script || options.script_info.is_some();
+    let mut plugins = Plugins::load(options, &mod_identifier, &mod_name)?;
+
+    let mut plans = vec![];
+    for video in &videos {
+        let ProbeInfo { duration: input_duration, framerate: input_framerate, sample_rate, .. } = probe(&video.path)?;
+        let input_duration = input_duration.ok_or_else(|| AutovideoError::ProbeFailed { input: video.path.clone() })?;
+        let frames = video.frame_amount(input_duration);
+        let duration = frames as f64 / video.framerate as f64;
+        let frames_per_grid = video.geometry.frames_per_grid();
+        let grids = frames.div_ceil(frames_per_grid);
+        let screens: Vec<ScreenPlan> = Screen::ALL.into_iter().map(|screen| ScreenPlan {
+            screen,
+            max_duration: screen.max_grids().map(|max_grids| (max_grids * frames_per_grid) as f64 / video.framerate as f64),
+            truncated: screen.max_grids().is_some_and(|max_grids| grids > max_grids),
+        }).collect();
+        let plays_on = |screen: &Screen| screens.iter().any(|plan| plan.screen == *screen && !plan.truncated);
+        let trimmed = video.trim.is_some_and(|trim| trim.start.is_some_and(|start| start > 0.0) || trim.end.is_some_and(|end| end < input_duration));
+
+        // The grids of a frame size are removed again when the video turns out too long for all of its screens
+        let texture_bytes = video.grid_sets.iter()
+            .filter(|set| set.screens.iter().any(plays_on))
+            .map(|set| grids as u64 * options.texture.estimate(set.geometry.width, set.geometry.height).bytes)
+            .sum();
+        let audio_name = format!("{}.wav", video.identifier);
+        let audio_bytes = sample_rate.map_or(0, |rate| options.audio_format.wav_size(rate, duration));
+
+        if !generate_script {
+            let plugin_video = PluginVideo { video_id: &video.identifier, video_name: &video.name, audio_name: &audio_name };
+            plugins.add_video(&plugin_video, plays_on(&Screen::DriveIn))?;
+        }
+
+        plans.push(VideoPlan {
+            name: video.name.clone(),
+            path: video.path.clone(),
+            input_duration,
+            input_framerate,
+            duration,
+            framerate: video.framerate,
+            trimmed,
+            truncated: trimmed || screens.iter().any(|plan| plan.truncated),
+            frames,
+            grids,
+            screens,
+            texture_bytes,
+            audio_bytes,
+        });
+    }
+    Ok(Plan {
+        texture_bytes: plans.iter().map(|plan| plan.texture_bytes).sum(),
+        audio_bytes: plans.iter().map(|plan| plan.audio_bytes).sum(),
+        videos: plans,
+    })
+}
@@ -0,0 +1,57 @@
+use std::path::Path;
+use std::process::Command;
+use serde::Deserialize;
+use crate::error::{AutovideoError, Result};
+
+/// What ffprobe tells about an input
+pub struct ProbeInfo {
+    /// Length in seconds, missing for some streamed formats
+    pub duration: Option<f64>,
+    /// Average framerate of the video stream
+    pub framerate: Option<f64>,
+    pub has_sound: bool,
+    pub sample_rate: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct ProbeOutput {
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+    format: Option<ProbeFormat>,
+}
+
+#[derive(Deserialize)]
+struct ProbeStream {
+    codec_type: Option<String>,
+    /// A fraction like `30000/1001`, `0/0` when unknown
+    avg_frame_rate: Option<String>,
+    sample_rate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ProbeFormat {
+    duration: Option<String>,
+}
+
+pub fn probe(input: &Path) -> Result<ProbeInfo> {
+    let probe = Command::new("ffprobe").arg("-i").arg(input)
+        .args(["-show_entries", "format=duration:stream=codec_type,avg_frame_rate,sample_rate", "-of", "json", "-loglevel", "error"])
+        .output()
+        .map_err(|e| AutovideoError::FfmpegMissing { program: "ffprobe".to_string(), message: e.to_string() })?;
+    if !probe.status.success() {
+        return Err(AutovideoError::ProbeFailed { input: input.to_path_buf() });
+    }
+    let output: ProbeOutput = serde_json::from_slice(&probe.stdout).map_err(|_| AutovideoError::ProbeFailed { input: input.to_path_buf() })?;
+    let stream = |codec_type: &str| output.streams.iter().find(|stream| stream.codec_type.as_deref() == Some(codec_type));
+    let framerate = stream("video").and_then(|stream| stream.avg_frame_rate.as_deref()).and_then(|rate| {
+        let (numerator, denominator) = rate.split_once('/')?;
+        let (numerator, denominator): (f64, f64) = (numerator.parse().ok()?, denominator.parse().ok()?);
+        (numerator > 0.0 && denominator > 0.0).then(|| numerator / denominator)
+    });
+    Ok(ProbeInfo {
+        duration: output.format.as_ref().and_then(|format| format.duration.as_deref()).and_then(|duration| duration.parse().ok()),
+        framerate,
+        has_sound: stream("audio").is_some(),
+        sample_rate: stream("audio").and_then(|stream| stream.sample_rate.as_deref()).and_then(|rate| rate.parse().ok()),
+    })
+}
@@ -0,0 +1,92 @@
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+use serde::Serialize;
+
+/// Least time between two events of the same stage of a video, so frontends aren't flooded with one event per frame
+const REPORT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Step of a conversion a progress event comes from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Stage {
+    /// ffmpeg decodes the video into frames and audio
+    Extracting,
+    /// The frames are read and put into grids, which are encoded as they fill up
+    Reading,
+    /// Waiting for the last grids to be encoded
+    EncodingGrids,
+    /// The video was converted, or skipped because it had not changed
+    Done,
+    WritingMeshes,
+    WritingPlugin,
+    PackingArchives,
+    WritingInstaller,
+}
+
+/// Amount of things done in a stage, next to how many there are in total once that is known
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Count {
+    pub done: usize,
+    pub total: Option<usize>,
+}
+
+/// What a conversion is busy with, given to the callback of [`process_videos`](crate::process_videos)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct ProgressEvent {
+    pub stage: Stage,
+    /// Index of the video in the job, `None` for the stages writing the whole mod
+    pub video: Option<usize>,
+    /// Frames decoded or read, the total is worked out from the duration of the video until all of them are extracted
+    pub frames: Option<Count>,
+    /// Grid textures encoded over all frame sizes of the video
+    pub grids: Option<Count>,
+}
+
+impl ProgressEvent {
+    pub(crate) fn of_mod(stage: Stage) -> ProgressEvent {
+        ProgressEvent { stage, video: None, frames: None, grids: None }
+    }
+}
+
+/// Sends the progress of converting a single video, keeping track of its totals
+pub(crate) struct VideoProgress<'a> {
+    send: &'a (dyn Fn(ProgressEvent) + Sync),
+    video: usize,
+    frames_per_grid: usize,
+    grid_sets: usize,
+    frames_total: Cell<Option<usize>>,
+    last_report: Cell<Option<(Stage, Instant)>>,
+}
+
+impl<'a> VideoProgress<'a> {
+    pub fn new(send: &'a (dyn Fn(ProgressEvent) + Sync), video: usize, frames_per_grid: usize, grid_sets: usize) -> Self {
+        VideoProgress { send, video, frames_per_grid, grid_sets, frames_total: Cell::new(None), last_report: Cell::new(None) }
+    }
+
+    pub fn set_frames_total(&self, frames: usize) {
+        self.frames_total.set(Some(frames));
+    }
+
+    /// Reports the frames and grids done so far, unless the same stage was reported a moment ago
+    pub fn update(&self, stage: Stage, frames: Option<usize>, grids: Option<usize>) {
+        if let Some((last_stage, time)) = self.last_report.get() {
+            if last_stage == stage && time.elapsed() < REPORT_INTERVAL {
+                return;
+            }
+        }
+        self.report(stage, frames, grids);
+    }
+
+    /// Reports the frames and grids done so far
+    pub fn report(&self, stage: Stage, frames: Option<usize>, grids: Option<usize>) {
+        let frames_total = self.frames_total.get();
+        let grids_total = frames_total.map(|frames| frames.div_ceil(self.frames_per_grid) * self.grid_sets);
+        (self.send)(ProgressEvent {
+            stage,
+            video: Some(self.video),
+            frames: frames.map(|done| Count { done, total: frames_total }),
+            grids: grids.map(|done| Count { done, total: grids_total }),
+        });
+        self.last_report.set(Some((stage, Instant::now())));
+    }
+}
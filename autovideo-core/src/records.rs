@@ -0,0 +1,499 @@
+use std::collections::HashSet;
+use crate::esp::{GroupEntry, Plugin, Record, Subrecord};
+
+const FALLOUT4: &str = "Fallout4.esm";
+pub(crate) const VOTW_MASTER: &str = "VotWMaster.esm";
+
+// Object ids of records in VotWMaster.esm and Fallout4.esm that the generated records point to
+const VOTW_MAIN_QUEST: u32 = 0x004C82;
+const VOTW_QUEST_QUEUE_LIST: u32 = 0x00B720;
+const VOTW_TV_MEDIA_LIST: u32 = 0x000802;
+const VOTW_TV_VIDEO_LIST: u32 = 0x000803;
+const VOTW_PR_MEDIA_LIST: u32 = 0x001ED9;
+const VOTW_PR_VIDEO_LIST: u32 = 0x001EDA;
+const VOTW_DI_MEDIA_LIST: u32 = 0x001ED5;
+const VOTW_DI_VIDEO_LIST: u32 = 0x001ED6;
+const VOTW_DI_SOUND_LIST: u32 = 0x001ED8;
+const VOTW_WORKBENCH_KEYWORD: u32 = 0x0035CF;
+const VOTW_FILM_REEL_TRANSFORM: u32 = 0x00B71D;
+const VOTW_SOUND_CATEGORY: u32 = 0x004C7E;
+const HOLOTAPE_TRANSFORM: u32 = 0x0995A5;
+const PICK_UP_SOUND: u32 = 0x0BBFA8;
+const PUT_DOWN_SOUND: u32 = 0x0BBFA9;
+const TV_SOUND_OUTPUT: u32 = 0x06CB4F;
+const DI_SOUND_OUTPUT: u32 = 0x03F789;
+const COMPONENT_PLASTIC: u32 = 0x01FA91;
+const COMPONENT_CIRCUITRY: u32 = 0x01FA9B;
+const COMPONENT_STEEL: u32 = 0x01FAB7;
+const SOUND_DESCRIPTOR_TYPE: u32 = 0x1EEF540A;
+
+// Strings the old template esps used as placeholders for records that were not filled in yet
+const PLACEHOLDERS: [&[u8]; 5] = [b"AUTOVIDENT", b"AUTOPIDENT", b"AUTOSIDENT", b"ZAUTONIDEN", b"AUTOIDENTSOUND"];
+
+const MASTERS: [&str; 2] = [FALLOUT4, VOTW_MASTER];
+const DI_DESCRIPTION: &str = "Videos of the Wasteland Plugin. Groups anything relative to Starlight Drive-In and film reels";
+
+/// Everything the plugin needs to know about a converted video
+pub struct PluginVideo<'a> {
+    pub video_id: &'a str,
+    pub video_name: &'a str,
+    pub audio_name: &'a str,
+}
+
+/// Load order indexes of the masters, as seen from the plugin
+struct Masters {
+    fallout4: u32,
+    votw: u32,
+}
+
+impl Masters {
+    fn of(plugin: &Plugin) -> Result<Self, String> {
+        let fallout4 = plugin.master_index(FALLOUT4).ok_or(format!("Esp does not have {FALLOUT4} as master"))?;
+        let votw = plugin.master_index(VOTW_MASTER).ok_or(format!("Esp does not have {VOTW_MASTER} as master"))?;
+        Ok(Masters { fallout4, votw })
+    }
+
+    fn fallout4(&self, object_id: u32) -> u32 {
+        (self.fallout4 << 24) | object_id
+    }
+
+    fn votw(&self, object_id: u32) -> u32 {
+        (self.votw << 24) | object_id
+    }
+}
+
+/// The FormID Lists of a TV plugin that VotW merges into its own lists
+pub struct TvLists {
+    holotapes: u32,
+    tv_screens: u32,
+    pr_screens: u32,
+}
+
+/// The FormID Lists of a DriveIn plugin that VotW merges into its own lists
+pub struct DriveInLists {
+    reels: u32,
+    screens: u32,
+    sounds: u32,
+}
+
+pub fn new_tv_plugin(mod_id: &str, mod_name: &str) -> Plugin {
+    let mut plugin = Plugin::new("DEFAULT", None, &MASTERS);
+    let masters = Masters { fallout4: 0, votw: 1 };
+    for label in [b"KYWD", b"ACTI", b"NOTE", b"QUST", b"FLST", b"SNDR", b"COBJ"] {
+        plugin.group_mut(label);
+    }
+
+    add_category_keyword(&mut plugin, &format!("VotW_{mod_id}Holotapes_Workstation"), &format!("Holotapes - {mod_name}"));
+
+    let tv_media_list = add_form_list(&mut plugin, &format!("VotW_TV{mod_id}MediaLIST"));
+    let tv_video_list = add_form_list(&mut plugin, &format!("VotW_TV{mod_id}VideoLIST"));
+    let pr_video_list = add_form_list(&mut plugin, &format!("VotW_PR{mod_id}VideoLIST"));
+
+    add_list_quest(&mut plugin, &format!("VotW_TVAdd{mod_id}QST"), None, 0x63, &[
+        ("MySoundLIST", 0),
+        ("MyMediaLIST", tv_media_list),
+        ("MainMediaLIST", masters.votw(VOTW_TV_MEDIA_LIST)),
+        ("MainQuest", masters.votw(VOTW_MAIN_QUEST)),
+        ("MainSoundLIST", 0),
+        ("MainVideoLIST", masters.votw(VOTW_TV_VIDEO_LIST)),
+        ("MyVideoLIST", tv_video_list),
+        ("QuestQueueLIST", masters.votw(VOTW_QUEST_QUEUE_LIST)),
+    ]);
+    add_list_quest(&mut plugin, &format!("VotW_PRAdd{mod_id}QST"), None, 0xD4, &[
+        ("MyMediaLIST", tv_media_list),
+        ("MySoundLIST", 0),
+        ("MainVideoLIST", masters.votw(VOTW_PR_VIDEO_LIST)),
+        ("MainQuest", masters.votw(VOTW_MAIN_QUEST)),
+        ("MainSoundLIST", 0),
+        ("MainMediaLIST", masters.votw(VOTW_PR_MEDIA_LIST)),
+        ("MyVideoLIST", pr_video_list),
+        ("QuestQueueLIST", masters.votw(VOTW_QUEST_QUEUE_LIST)),
+    ]);
+    plugin
+}
+
+/// Finds the holotape, TV and projector lists through the quests that register them with VotW
+pub fn find_tv_lists(plugin: &Plugin) -> Result<TvLists, String> {
+    let masters = Masters::of(plugin)?;
+    let tv_quest = list_quest_properties(plugin, masters.votw(VOTW_TV_VIDEO_LIST)).ok_or("Esp has no quest adding videos to the VotW TV list")?;
+    let pr_quest = list_quest_properties(plugin, masters.votw(VOTW_PR_VIDEO_LIST)).ok_or("Esp has no quest adding videos to the VotW Projector list")?;
+    Ok(TvLists {
+        holotapes: property(&tv_quest, "MyMediaLIST")?,
+        tv_screens: property(&tv_quest, "MyVideoLIST")?,
+        pr_screens: property(&pr_quest, "MyVideoLIST")?,
+    })
+}
+
+pub fn add_tv_video(plugin: &mut Plugin, mod_id: &str, mod_name: &str, video: &PluginVideo) -> Result<(), String> {
+    let PluginVideo { video_id, video_name, audio_name } = video;
+    let masters = Masters::of(plugin)?;
+    let lists = find_tv_lists(plugin)?;
+    let category_id = format!("VotW_{mod_id}Holotapes_Workstation");
+    let category = match find_category(plugin, lists.holotapes, &category_id) {
+        Some(category) => category,
+        None => add_category_keyword(plugin, &category_id, &format!("Holotapes - {mod_name}"))
+    };
+
+    let tv_screen = plugin.allocate_form_id();
+    plugin.add_record(screen_record(tv_screen, &format!("VotW_TV{mod_id}{video_id}Screen"), [0xFFE4, 0xFFE9, 0x0000, 0x001C, 0x0025, 0x0069], &format!("Videos\\Television\\{mod_id}\\{video_id}.nif")));
+    let pr_screen = plugin.allocate_form_id();
+    plugin.add_record(screen_record(pr_screen, &format!("VotW_PR{mod_id}{video_id}Screen"), [0xFF8B, 0xFFE4, 0xFF8B, 0x0075, 0x001C, 0x0075], &format!("Videos\\Projector\\{mod_id}\\{video_id}.nif")));
+
+    let holotape = plugin.allocate_form_id();
+    plugin.add_record(Record::new(b"NOTE", holotape, vec![
+        Subrecord::zstring(b"EDID", &format!("VotW_{mod_id}{video_id}Tape")),
+        object_bounds([0xFFFD, 0xFFFD, 0x0000, 0x0003, 0x0003, 0x0001]),
+        Subrecord::u32(b"PTRN", masters.fallout4(HOLOTAPE_TRANSFORM)),
+        Subrecord::zstring(b"FULL", &format!("[VotWH] {mod_name} - {video_name}")),
+        Subrecord::zstring(b"MODL", "Props\\Holotape_Prop.nif"),
+        empty_texture_hashes(),
+        Subrecord::u32(b"YNAM", masters.fallout4(PICK_UP_SOUND)),
+        Subrecord::u32(b"ZNAM", masters.fallout4(PUT_DOWN_SOUND)),
+        Subrecord::new(b"DNAM", vec![0]),
+        Subrecord::new(b"DATA", vec![0; 8]),
+    ]));
+
+    let sound = plugin.allocate_form_id();
+    plugin.add_record(sound_record(&masters, sound, &format!("VotW_{mod_id}{video_id}Sound"), &format!("data\\Sound\\Videos\\{mod_id}\\{audio_name}"), masters.fallout4(TV_SOUND_OUTPUT)));
+
+    let recipe = plugin.allocate_form_id();
+    let components = [(masters.fallout4(COMPONENT_PLASTIC), 2), (masters.fallout4(COMPONENT_CIRCUITRY), 1), (masters.fallout4(COMPONENT_STEEL), 3)];
+    plugin.add_record(recipe_record(&masters, recipe, &format!("VotW_{mod_id}{video_id}Craft"), &components, holotape, category));
+
+    add_to_form_list(plugin, lists.holotapes, holotape)?;
+    add_to_form_list(plugin, lists.tv_screens, tv_screen)?;
+    add_to_form_list(plugin, lists.pr_screens, pr_screen)
+}
+
+pub fn new_drivein_plugin(mod_id: &str, mod_name: &str) -> Plugin {
+    let mut plugin = Plugin::new("DEFAULT", Some(DI_DESCRIPTION), &MASTERS);
+    let masters = Masters { fallout4: 0, votw: 1 };
+    for label in [b"KYWD", b"ACTI", b"MISC", b"QUST", b"FLST", b"SNDR", b"COBJ"] {
+        plugin.group_mut(label);
+    }
+
+    add_category_keyword(&mut plugin, &format!("VotW_DI{mod_id}FilmReels"), &format!("Film Reels - {mod_name}"));
+
+    let media_list = add_form_list(&mut plugin, &format!("VotW_DI{mod_id}MediaLIST"));
+    let video_list = add_form_list(&mut plugin, &format!("VotW_DI{mod_id}VideoLIST"));
+    let sound_list = add_form_list(&mut plugin, &format!("VotW_DI{mod_id}SoundLIST"));
+
+    add_list_quest(&mut plugin, &format!("VotW_DIAdd{mod_id}QST"), Some("Add Special Films To Main FormID List"), 0x55, &[
+        ("MainMediaLIST", masters.votw(VOTW_DI_MEDIA_LIST)),
+        ("MainVideoLIST", masters.votw(VOTW_DI_VIDEO_LIST)),
+        ("MySoundLIST", sound_list),
+        ("MainQuest", masters.votw(VOTW_MAIN_QUEST)),
+        ("MainSoundLIST", masters.votw(VOTW_DI_SOUND_LIST)),
+        ("MyMediaLIST", media_list),
+        ("MyVideoLIST", video_list),
+        ("QuestQueueLIST", masters.votw(VOTW_QUEST_QUEUE_LIST)),
+    ]);
+    plugin
+}
+
+/// Finds the film reel, screen and sound lists through the quest that registers them with VotW
+pub fn find_drivein_lists(plugin: &Plugin) -> Result<DriveInLists, String> {
+    let masters = Masters::of(plugin)?;
+    let quest = list_quest_properties(plugin, masters.votw(VOTW_DI_VIDEO_LIST)).ok_or("Esp has no quest adding videos to the VotW DriveIn list")?;
+    Ok(DriveInLists {
+        reels: property(&quest, "MyMediaLIST")?,
+        screens: property(&quest, "MyVideoLIST")?,
+        sounds: property(&quest, "MySoundLIST")?,
+    })
+}
+
+pub fn add_drivein_video(plugin: &mut Plugin, mod_id: &str, mod_name: &str, video: &PluginVideo) -> Result<(), String> {
+    let PluginVideo { video_id, video_name, audio_name } = video;
+    let masters = Masters::of(plugin)?;
+    let lists = find_drivein_lists(plugin)?;
+    let category_id = format!("VotW_DI{mod_id}FilmReels");
+    let category = match find_category(plugin, lists.reels, &category_id) {
+        Some(category) => category,
+        None => add_category_keyword(plugin, &category_id, &format!("Film Reels - {mod_name}"))
+    };
+
+    let screen = plugin.allocate_form_id();
+    plugin.add_record(screen_record(screen, &format!("VotW_DI{mod_id}{video_id}Video"), [0xFBFE, 0xFF6D, 0x0000, 0x0402, 0xFFCF, 0x03FD], &format!("Videos\\DriveIn\\{mod_id}\\{video_id}.nif")));
+
+    let reel = plugin.allocate_form_id();
+    let mut reel_data = 50u32.to_le_bytes().to_vec();
+    reel_data.extend_from_slice(&10f32.to_le_bytes());
+    plugin.add_record(Record::new(b"MISC", reel, vec![
+        Subrecord::zstring(b"EDID", &format!("VotW_DI{mod_id}{video_id}Reel")),
+        object_bounds([0xFFE0, 0xFFE0, 0xFFF9, 0x0020, 0x0020, 0x0007]),
+        Subrecord::u32(b"PTRN", masters.votw(VOTW_FILM_REEL_TRANSFORM)),
+        Subrecord::zstring(b"FULL", &format!("[VotWR] {mod_name} - {video_name}")),
+        Subrecord::zstring(b"MODL", "Props\\FilmReel.nif"),
+        empty_texture_hashes(),
+        Subrecord::u32(b"YNAM", masters.fallout4(PICK_UP_SOUND)),
+        Subrecord::u32(b"ZNAM", masters.fallout4(PUT_DOWN_SOUND)),
+        Subrecord::new(b"DATA", reel_data),
+    ]));
+
+    let sound = plugin.allocate_form_id();
+    plugin.add_record(sound_record(&masters, sound, &format!("VotW_DI{mod_id}{video_id}Sound"), &format!("data\\Sound\\Videos\\{mod_id}\\{audio_name}"), masters.fallout4(DI_SOUND_OUTPUT)));
+
+    let recipe = plugin.allocate_form_id();
+    let components = [(masters.fallout4(COMPONENT_STEEL), 2), (masters.fallout4(COMPONENT_PLASTIC), 3)];
+    plugin.add_record(recipe_record(&masters, recipe, &format!("VotW_DI{mod_id}{video_id}Craft"), &components, reel, category));
+
+    add_to_form_list(plugin, lists.reels, reel)?;
+    add_to_form_list(plugin, lists.screens, screen)?;
+    add_to_form_list(plugin, lists.sounds, sound)
+}
+
+/// Removes records that an old template esp still had reserved for videos, together with their recipes and list entries
+pub fn remove_placeholders(plugin: &mut Plugin) {
+    let is_placeholder = |record: &Record| record.subrecords.iter()
+        .any(|s| PLACEHOLDERS.iter().any(|p| s.data.windows(p.len()).any(|w| w == *p)));
+    let mut removed: HashSet<u32> = plugin.groups.iter()
+        .flat_map(|g| g.records())
+        .filter(|r| is_placeholder(r))
+        .map(|r| r.form_id)
+        .collect();
+    removed.extend(plugin.records(*b"COBJ")
+        .filter(|r| r.subrecord(b"CNAM").and_then(|c| c.as_u32()).is_some_and(|c| removed.contains(&c)))
+        .map(|r| r.form_id)
+        .collect::<Vec<_>>());
+
+    for group in &mut plugin.groups {
+        group.entries.retain(|e| !matches!(e, GroupEntry::Record(record) if removed.contains(&record.form_id)));
+        for record in group.records_mut().filter(|r| &r.signature == b"FLST") {
+            record.subrecords.retain(|s| &s.signature != b"LNAM" || !s.as_u32().is_some_and(|id| removed.contains(&id)));
+        }
+    }
+}
+
+/// Object properties of the VotW_AddToListsScript quest that merges into the given main video list
+fn list_quest_properties(plugin: &Plugin, main_video_list: u32) -> Option<Vec<(String, u32)>> {
+    plugin.records(*b"QUST")
+        .filter_map(|r| script_properties(&r.subrecord(b"VMAD")?.data))
+        .find(|properties| properties.iter().any(|(name, form_id)| name == "MainVideoLIST" && *form_id == main_video_list))
+}
+
+fn property(properties: &[(String, u32)], name: &str) -> Result<u32, String> {
+    properties.iter()
+        .find(|(n, form_id)| n == name && *form_id != 0)
+        .map(|(_, form_id)| *form_id)
+        .ok_or(format!("VotW quest in esp has no {name} property"))
+}
+
+/// Reads the object properties of all scripts in a VMAD subrecord, skipping properties of other types
+fn script_properties(vmad: &[u8]) -> Option<Vec<(String, u32)>> {
+    let mut reader = VmadReader { data: vmad, position: 0 };
+    reader.take(2)?;
+    let object_format = reader.u16()?;
+    let mut properties = vec![];
+    for _ in 0..reader.u16()? {
+        reader.wstring()?;
+        reader.take(1)?;
+        for _ in 0..reader.u16()? {
+            let name = reader.wstring()?;
+            let property_type = reader.take(2)?[0];
+            match property_type {
+                1 => properties.push((name, reader.object(object_format)?)),
+                2 => { reader.wstring()?; }
+                3 | 4 => { reader.take(4)?; }
+                5 => { reader.take(1)?; }
+                11 => for _ in 0..reader.u32()? { reader.object(object_format)?; },
+                12 => for _ in 0..reader.u32()? { reader.wstring()?; },
+                13 | 14 => { let count = reader.u32()?; reader.take(count as usize * 4)?; }
+                15 => { let count = reader.u32()?; reader.take(count as usize)?; }
+                _ => return None
+            }
+        }
+    }
+    Some(properties)
+}
+
+struct VmadReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl VmadReader<'_> {
+    fn take(&mut self, length: usize) -> Option<&[u8]> {
+        let bytes = self.data.get(self.position..self.position + length)?;
+        self.position += length;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn wstring(&mut self) -> Option<String> {
+        let length = self.u16()? as usize;
+        Some(String::from_utf8_lossy(self.take(length)?).to_string())
+    }
+
+    fn object(&mut self, object_format: u16) -> Option<u32> {
+        let bytes = self.take(8)?;
+        // Format 1 stores the FormID before the alias, format 2 after it
+        let form_id = if object_format == 1 { &bytes[0..4] } else { &bytes[4..8] };
+        Some(u32::from_le_bytes(form_id.try_into().ok()?))
+    }
+}
+
+/// The recipe category keyword used by the existing recipes of the given list, or else the keyword with the given editor id
+fn find_category(plugin: &Plugin, list: u32, editor_id: &str) -> Option<u32> {
+    let entries: HashSet<u32> = plugin.records(*b"FLST")
+        .find(|r| r.form_id == list)?
+        .subrecords.iter()
+        .filter(|s| &s.signature == b"LNAM")
+        .filter_map(|s| s.as_u32())
+        .collect();
+    plugin.records(*b"COBJ")
+        .find(|r| r.subrecord(b"CNAM").and_then(|c| c.as_u32()).is_some_and(|c| entries.contains(&c)))
+        .and_then(|r| r.subrecord(b"FNAM")?.as_u32())
+        // New plugins have the keyword before any recipe uses it
+        .or_else(|| plugin.records(*b"KYWD")
+            .find(|r| r.subrecord(b"EDID").and_then(|e| e.as_zstring()) == Some(editor_id))
+            .map(|r| r.form_id))
+}
+
+fn add_category_keyword(plugin: &mut Plugin, editor_id: &str, name: &str) -> u32 {
+    let form_id = plugin.allocate_form_id();
+    plugin.add_record(Record::new(b"KYWD", form_id, vec![
+        Subrecord::zstring(b"EDID", editor_id),
+        Subrecord::u32(b"CNAM", 0x00FFFFFF),
+        Subrecord::u32(b"TNAM", 9),
+        Subrecord::zstring(b"FULL", name),
+    ]));
+    form_id
+}
+
+fn add_form_list(plugin: &mut Plugin, editor_id: &str) -> u32 {
+    let form_id = plugin.allocate_form_id();
+    plugin.add_record(Record::new(b"FLST", form_id, vec![Subrecord::zstring(b"EDID", editor_id)]));
+    form_id
+}
+
+fn add_to_form_list(plugin: &mut Plugin, list: u32, entry: u32) -> Result<(), String> {
+    match plugin.record_by_form_id_mut(b"FLST", list) {
+        Some(list) => {
+            list.subrecords.push(Subrecord::u32(b"LNAM", entry));
+            Ok(())
+        }
+        None => Err(format!("FormID List {list:08X} not found in esp"))
+    }
+}
+
+/// Adds a start game enabled quest running VotW_AddToListsScript, which merges our lists into the ones of VotWMaster.esm
+fn add_list_quest(plugin: &mut Plugin, editor_id: &str, name: Option<&str>, priority: u8, properties: &[(&str, u32)]) {
+    let mut vmad = vec![];
+    vmad.extend_from_slice(&6i16.to_le_bytes());
+    vmad.extend_from_slice(&2i16.to_le_bytes());
+    vmad.extend_from_slice(&1u16.to_le_bytes());
+    push_wstring(&mut vmad, "VotW_AddToListsScript");
+    vmad.push(0);
+    vmad.extend_from_slice(&(properties.len() as u16).to_le_bytes());
+    for (property, form_id) in properties {
+        push_wstring(&mut vmad, property);
+        vmad.extend_from_slice(&[1, 1, 0, 0, 0xFF, 0xFF]);
+        vmad.extend_from_slice(&form_id.to_le_bytes());
+    }
+
+    let mut subrecords = vec![
+        Subrecord::zstring(b"EDID", editor_id),
+        Subrecord::new(b"VMAD", vmad),
+    ];
+    if let Some(name) = name {
+        subrecords.push(Subrecord::zstring(b"FULL", name));
+    }
+    subrecords.extend([
+        Subrecord::new(b"DNAM", vec![0x11, 0x01, 0x00, priority, 0, 0, 0, 0, 0, 0, 0, 0]),
+        Subrecord::new(b"NEXT", vec![]),
+        Subrecord::u32(b"ANAM", 0),
+    ]);
+    let form_id = plugin.allocate_form_id();
+    plugin.add_record(Record::new(b"QUST", form_id, subrecords));
+}
+
+fn push_wstring(data: &mut Vec<u8>, value: &str) {
+    data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    data.extend_from_slice(value.as_bytes());
+}
+
+fn object_bounds(bounds: [u16; 6]) -> Subrecord {
+    Subrecord::new(b"OBND", bounds.iter().flat_map(|b| b.to_le_bytes()).collect())
+}
+
+fn empty_texture_hashes() -> Subrecord {
+    let mut data = vec![0; 20];
+    data[0] = 4;
+    Subrecord::new(b"MODT", data)
+}
+
+fn screen_record(form_id: u32, editor_id: &str, bounds: [u16; 6], model: &str) -> Record {
+    Record::new(b"ACTI", form_id, vec![
+        Subrecord::zstring(b"EDID", editor_id),
+        object_bounds(bounds),
+        Subrecord::zstring(b"MODL", model),
+        empty_texture_hashes(),
+        Subrecord::u32(b"PNAM", 0x00334CCC),
+        Subrecord::new(b"FNAM", vec![0, 0]),
+    ])
+}
+
+fn sound_record(masters: &Masters, form_id: u32, editor_id: &str, audio_path: &str, output_model: u32) -> Record {
+    Record::new(b"SNDR", form_id, vec![
+        Subrecord::zstring(b"EDID", editor_id),
+        Subrecord::u32(b"CNAM", SOUND_DESCRIPTOR_TYPE),
+        Subrecord::u32(b"GNAM", masters.votw(VOTW_SOUND_CATEGORY)),
+        Subrecord::zstring(b"ANAM", audio_path),
+        Subrecord::u32(b"ONAM", output_model),
+        Subrecord::u32(b"LNAM", 0),
+        Subrecord::new(b"BNAM", vec![0, 0, 0x80, 0, 0, 0]),
+    ])
+}
+
+fn recipe_record(masters: &Masters, form_id: u32, editor_id: &str, components: &[(u32, u32)], created_object: u32, category: u32) -> Record {
+    Record::new(b"COBJ", form_id, vec![
+        Subrecord::zstring(b"EDID", editor_id),
+        Subrecord::new(b"FVPA", components.iter().flat_map(|(component, count)| [component.to_le_bytes(), count.to_le_bytes()].concat()).collect()),
+        Subrecord::new(b"DESC", vec![0]),
+        Subrecord::u32(b"CNAM", created_object),
+        Subrecord::u32(b"BNAM", masters.votw(VOTW_WORKBENCH_KEYWORD)),
+        Subrecord::u32(b"FNAM", category),
+        Subrecord::u32(b"INTV", 1),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keywords(plugin: &Plugin, editor_id: &str) -> usize {
+        plugin.records(*b"KYWD").filter(|r| r.subrecord(b"EDID").and_then(|e| e.as_zstring()) == Some(editor_id)).count()
+    }
+
+    fn video(video_id: &str) -> PluginVideo<'_> {
+        PluginVideo { video_id, video_name: video_id, audio_name: "Video.wav" }
+    }
+
+    #[test]
+    fn new_plugins_reuse_their_category_keyword() {
+        let mut tv = new_tv_plugin("Mod", "Mod");
+        let mut drive_in = new_drivein_plugin("Mod", "Mod");
+        for video_id in ["First", "Second"] {
+            add_tv_video(&mut tv, "Mod", "Mod", &video(video_id)).unwrap();
+            add_drivein_video(&mut drive_in, "Mod", "Mod", &video(video_id)).unwrap();
+        }
+        assert_eq!(keywords(&tv, "VotW_ModHolotapes_Workstation"), 1);
+        assert_eq!(keywords(&drive_in, "VotW_DIModFilmReels"), 1);
+    }
+
+    #[test]
+    fn written_plugins_keep_their_category_keyword() {
+        let mut tv = new_tv_plugin("Mod", "Mod");
+        add_tv_video(&mut tv, "Mod", "Mod", &video("First")).unwrap();
+        let mut tv = Plugin::parse(&tv.to_bytes()).unwrap();
+        add_tv_video(&mut tv, "Mod", "Mod", &video("Second")).unwrap();
+        assert_eq!(keywords(&tv, "VotW_ModHolotapes_Workstation"), 1);
+    }
+}
@@ -1,8 +1,11 @@
 use std::fs;
+use std::path::Path;
+use crate::error::{PathContext, Result};
 use crate::ScriptInfo;
 use crate::utility::user_input;
 
-pub fn generate_script(mod_name: &str, mod_id: &str, video_data: &[(String, String, String, bool)], script_info: Option<ScriptInfo>) -> Result<(), String> {
+/// Writes the FO4Edit script, returning the file name of the esp it adds the videos to
+pub fn generate_script(mod_name: &str, mod_id: &str, video_data: &[(String, String, String, bool)], script_info: Option<ScriptInfo>, output_dir: &Path) -> Result<String> {
     let (esp_name, tv_record, pr_record, di_esp_name) = if let Some(script_info) = script_info {
         (script_info.esp_name, script_info.tv_record, script_info.pr_record, script_info.di_esp_name)
     } else {
@@ -23,6 +26,7 @@ pub fn generate_script(mod_name: &str, mod_id: &str, video_data: &[(String, Stri
     let mut video_handlers = String::new();
     let mut di_handlers = String::new();
     for (video_id, video_name, audio_name, has_drivein) in video_data {
+        let video_name = pascal_escape(video_name);
         video_handlers.push_str(&format!("  HandleVideo('{video_id}', '{video_name}', '{audio_name}');\n"));
         if di_enabled && *has_drivein {
             di_handlers.push_str(&format!("  HandleDIVideo('{video_id}', '{video_name}', '{audio_name}');\n"))
@@ -77,7 +81,7 @@ begin
   end;
   
   modId := '{mod_id}';
-  modName := '{mod_name}';
+  modName := '{}';
   
 {video_handlers}
 
@@ -148,7 +152,7 @@ begin
     AddMessage('NOTE record not found');
     Exit;
   end;
-  SetElementEditValues(NewNote, 'EDID', 'VotW_'+modName+videoName+'_Tape');
+  SetElementEditValues(NewNote, 'EDID', 'VotW_'+modId+videoId+'Tape');
   SetElementEditValues(NewNote, 'FULL', '[VotWH] '+modName+' - '+videoName);
   AddMessage('NOTE record added successfully.');
   AddToFormIDList(NoteFormList, NewNote);
@@ -159,7 +163,7 @@ begin
     AddMessage('TV ACTI record not found');
     Exit;
   end;
-  SetElementEditValues(NewTVACTI, 'EDID', 'VotW_TV'+modName+videoName+'Screen');
+  SetElementEditValues(NewTVACTI, 'EDID', 'VotW_TV'+modId+videoId+'Screen');
   SetElementEditValues(NewTVACTI, 'Model\\MODL', 'Videos\\Television\\'+modId+'\\'+videoId+'.nif');
   AddToFormIDList(TVFormList, NewTVACTI);
   
@@ -169,7 +173,7 @@ begin
     AddMessage('PR ACTI record not found');
     Exit;
   end;
-  SetElementEditValues(NewPRACTI, 'EDID', 'VotW_PR'+modName+videoName+'Screen');
+  SetElementEditValues(NewPRACTI, 'EDID', 'VotW_PR'+modId+videoId+'Screen');
   SetElementEditValues(NewPRACTI, 'Model\\MODL', 'Videos\\Projector\\'+modId+'\\'+videoId+'.nif');
   AddToFormIDList(PRFormList, NewPRACTI);
   
@@ -188,7 +192,7 @@ begin
     AddMessage('COBJ record not found');
     Exit;
   end;
-  SetElementEditValues(NewCOBJ, 'EDID', 'VotW_'+modName+videoName+'Craft');
+  SetElementEditValues(NewCOBJ, 'EDID', 'VotW_'+modId+videoId+'Craft');
   SetElementEditValues(NewCOBJ, 'CNAM', GetEditValue(NewNote));
 end;
 
@@ -204,7 +208,7 @@ begin
     AddMessage('TV ACTI record not found');
     Exit;
   end;
-  SetElementEditValues(NewScreen, 'EDID', 'VotW_DI'+modName+videoName+'Screen');
+  SetElementEditValues(NewScreen, 'EDID', 'VotW_DI'+modId+videoId+'Video');
   SetElementEditValues(NewScreen, 'Model\\MODL', 'Videos\\DriveIn\\'+modId+'\\'+videoId+'.nif');
   AddToFormIDList(ScreenFormList, NewScreen);
   
@@ -214,7 +218,7 @@ begin
     AddMessage('MISC record not found');
     Exit;
   end;
-  SetElementEditValues(NewMisc, 'EDID', 'VotW_DI'+modName+videoName+'Reel');
+  SetElementEditValues(NewMisc, 'EDID', 'VotW_DI'+modId+videoId+'Reel');
   SetElementEditValues(NewMisc, 'FULL', '[VotWR] '+modName+' - '+videoName);
   AddMessage('MISC record added successfully.');
   AddToFormIDList(MiscFormList, NewMisc);
@@ -225,7 +229,7 @@ begin
     AddMessage('COBJ record not found');
     Exit;
   end;
-  SetElementEditValues(NewCOBJ, 'EDID', 'VotW_DI'+modName+videoName+'Craft');
+  SetElementEditValues(NewCOBJ, 'EDID', 'VotW_DI'+modId+videoId+'Craft');
   SetElementEditValues(NewCOBJ, 'CNAM', GetEditValue(NewMisc));
   
   // Handle SNDR
@@ -234,7 +238,7 @@ begin
     AddMessage('SNDR record not found');
     Exit;
   end;
-  SetElementEditValues(NewSound, 'EDID', 'VotW_DI'+modName+videoName+'Sound');
+  SetElementEditValues(NewSound, 'EDID', 'VotW_DI'+modId+videoId+'Sound');
   SetEditValue(ElementByPath(ElementByIndex(ElementByPath(NewSound, 'Sounds'), 0), 'ANAM'), 'data\\sound\\Videos\\'+modId+'\\'+audioName);
   AddToFormIDList(SoundFormList, NewSound);
 end;
@@ -320,10 +324,14 @@ begin
     end;
   end;
 end;
-end.", if di_enabled { "True" } else { "False" });
+end.", pascal_escape(mod_name), if di_enabled { "True" } else { "False" });
 
-    match fs::write("output/script.txt", script_contents) {
-        Ok(_) => Ok(()),
-        Err(_) => Err("Failed to write script file!".to_string()),
-    }
-}
\ No newline at end of file
+    fs::create_dir_all(output_dir).at(output_dir)?;
+    let script_path = output_dir.join("script.txt");
+    fs::write(&script_path, script_contents).at(&script_path)?;
+    Ok(esp_name)
+}
+
+fn pascal_escape(string: &str) -> String {
+    string.replace('\'', "''")
+}
@@ -0,0 +1,159 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use image::RgbaImage;
+use image_dds::{dds_from_image, ImageFormat, Mipmaps, Quality};
+use serde::{Deserialize, Serialize};
+use crate::ba2::DdsHeader;
+use crate::error::{AutovideoError, PathContext, Result};
+
+/// Compression of the grid textures. All of them are read by Fallout 4 as they are
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextureFormat {
+    /// 4 bits per pixel, visible blocks on gradients and fine detail
+    #[default]
+    Bc1,
+    /// 8 bits per pixel, the colours of BC1 with a smooth alpha channel that the videos don't use
+    Bc3,
+    /// 8 bits per pixel, close to the original but by far the slowest to encode
+    Bc7,
+    /// 32 bits per pixel RGBA, exact and instant to write
+    Uncompressed,
+}
+
+impl TextureFormat {
+    pub const ALL: [TextureFormat; 4] = [TextureFormat::Bc1, TextureFormat::Bc3, TextureFormat::Bc7, TextureFormat::Uncompressed];
+
+    /// Bytes of an image of the given size, compressed formats store blocks of 4x4 pixels
+    fn image_size(&self, width: u64, height: u64) -> u64 {
+        let blocks = width.div_ceil(4) * height.div_ceil(4);
+        match self {
+            TextureFormat::Bc1 => blocks * 8,
+            TextureFormat::Bc3 | TextureFormat::Bc7 => blocks * 16,
+            TextureFormat::Uncompressed => width * height * 4,
+        }
+    }
+
+    /// Rough megapixels a single thread encodes per second at each encoder speed
+    fn megapixels_per_second(&self, speed: EncoderSpeed) -> f64 {
+        match (self, speed) {
+            (TextureFormat::Bc1, EncoderSpeed::Fast) => 200.0,
+            (TextureFormat::Bc1, EncoderSpeed::Normal) => 100.0,
+            (TextureFormat::Bc1, EncoderSpeed::Slow) => 40.0,
+            (TextureFormat::Bc3, EncoderSpeed::Fast) => 120.0,
+            (TextureFormat::Bc3, EncoderSpeed::Normal) => 60.0,
+            (TextureFormat::Bc3, EncoderSpeed::Slow) => 25.0,
+            (TextureFormat::Bc7, EncoderSpeed::Fast) => 15.0,
+            (TextureFormat::Bc7, EncoderSpeed::Normal) => 4.0,
+            (TextureFormat::Bc7, EncoderSpeed::Slow) => 0.8,
+            (TextureFormat::Uncompressed, _) => 1000.0,
+        }
+    }
+
+    /// Bytes before the image data, read from a small texture written in this format since the FourCC
+    /// decides whether the header carries the DX10 extension
+    fn header_size(&self) -> u64 {
+        let mut dds = Vec::new();
+        dds_from_image(&RgbaImage::new(4, 4), self.image_format(), Quality::Fast, Mipmaps::Disabled)
+            .map_err(|e| e.to_string())
+            .and_then(|image| image.write(&mut dds).map_err(|e| e.to_string()))
+            .and_then(|_| DdsHeader::parse(&dds))
+            .expect("every texture format is written with a known DDS header")
+            .size as u64
+    }
+
+    fn image_format(&self) -> ImageFormat {
+        match self {
+            TextureFormat::Bc1 => ImageFormat::BC1RgbaUnorm,
+            TextureFormat::Bc3 => ImageFormat::BC3RgbaUnorm,
+            TextureFormat::Bc7 => ImageFormat::BC7RgbaUnorm,
+            TextureFormat::Uncompressed => ImageFormat::Rgba8Unorm,
+        }
+    }
+}
+
+/// How hard the encoder looks for the best encoding of every block. Slower looks better, most of all with BC7
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncoderSpeed {
+    /// Good enough to check timing and framing in-game
+    Fast,
+    Normal,
+    #[default]
+    Slow,
+}
+
+impl EncoderSpeed {
+    fn quality(&self) -> Quality {
+        match self {
+            EncoderSpeed::Fast => Quality::Fast,
+            EncoderSpeed::Normal => Quality::Normal,
+            EncoderSpeed::Slow => Quality::Slow,
+        }
+    }
+}
+
+/// How the grid textures are encoded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TextureOptions {
+    pub format: TextureFormat,
+    pub speed: EncoderSpeed,
+    /// Adds smaller copies of every grid so screens seen from afar flicker less. They take a third more space,
+    /// and the smallest ones blend neighbouring frames into each other at their edges
+    pub mipmaps: bool,
+}
+
+/// Expected file size and encoding time of a single grid texture
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct TextureEstimate {
+    pub bytes: u64,
+    /// Time one thread takes for the grid, a rough guess that differs a lot between processors
+    pub seconds: f64,
+}
+
+impl TextureOptions {
+    /// Estimates a grid texture of the given size written with these options
+    pub fn estimate(&self, width: u32, height: u32) -> TextureEstimate {
+        let (mut width, mut height) = (width as u64, height as u64);
+        let (mut bytes, mut pixels) = (self.format.header_size(), 0);
+        loop {
+            bytes += self.format.image_size(width, height);
+            pixels += width * height;
+            if !self.mipmaps || (width == 1 && height == 1) {
+                break;
+            }
+            (width, height) = ((width / 2).max(1), (height / 2).max(1));
+        }
+        let seconds = pixels as f64 / 1_000_000.0 / self.format.megapixels_per_second(self.speed);
+        TextureEstimate { bytes, seconds }
+    }
+}
+
+pub fn save_as_dds(image: &RgbaImage, output_path: &Path, options: &TextureOptions) -> Result<()> {
+    let mipmaps = if options.mipmaps { Mipmaps::GeneratedAutomatic } else { Mipmaps::Disabled };
+    let dds_image = dds_from_image(image, options.format.image_format(), options.speed.quality(), mipmaps)
+        .map_err(|e| AutovideoError::Image { path: output_path.to_path_buf(), message: e.to_string() })?;
+    let mut writer = BufWriter::new(File::create(output_path).at(output_path)?);
+    dds_image.write(&mut writer).map_err(|e| AutovideoError::Image { path: output_path.to_path_buf(), message: e.to_string() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_match_the_written_files() {
+        let dir = std::env::temp_dir().join(format!("autovideo-texture-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let image = RgbaImage::new(64, 32);
+        for format in TextureFormat::ALL {
+            let options = TextureOptions { format, ..Default::default() };
+            let path = dir.join(format!("{format:?}.dds"));
+            save_as_dds(&image, &path, &options).unwrap();
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), options.estimate(64, 32).bytes, "{format:?}");
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
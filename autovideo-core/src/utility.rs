@@ -1,86 +1,31 @@
-use std::fs::File;
-use std::io::{BufWriter, stdin, stdout, Write};
-use image::{RgbaImage};
-use image_dds::{dds_from_image, ImageFormat, Mipmaps, Quality};
+use std::io::{stdin, stdout, Write};
 
-pub fn replace_all_strings_in_bytes(data: &mut [u8], to_replace: &str, replacement: &str) -> Result<(), String> {
-    let replacement = elongate(replacement, 'X', to_replace.len(), true)?;
-    let replacement_bytes = replacement.as_bytes();
-    let to_replace_bytes = to_replace.as_bytes();
-    let mut position = 0;
+/// Length of the identifiers used for folder, file and editor id names. Fixed so a name keeps the same identifier between runs,
+/// which lets a later run add to or replace the files of a mod. Mods made before identifiers were hashed used other names
+pub const IDENTIFIER_LENGTH: usize = 10;
 
-    while let Some(start) = data[position..].windows(to_replace_bytes.len()).position(|window| window == to_replace_bytes) {
-        let start = start + position;
-        position = start + to_replace_bytes.len();
-        data[start..position].copy_from_slice(replacement_bytes);
-    }
-    Ok(())
-}
-
-pub fn count_strings_in_bytes(data: &[u8], search_string: &str) -> u32 {
-    let mut counter = 0;
-    let search_string_bytes = search_string.as_bytes();
-    let mut position = 0;
-
-    while let Some(start) = data[position..].windows(search_string_bytes.len()).position(|window| window == search_string_bytes) {
-        let start = start + position;
-        position = start + search_string_bytes.len();
-        counter += 1;
-    }
-    counter
-}
-
-pub fn replace_first_string_in_bytes(data: &mut [u8], to_replace: &str, replacement: &str) -> Result<(), String> {
-    let replacement = elongate(replacement, 'X', to_replace.len(), true)?;
-    let replacement_bytes = replacement.as_bytes();
-    let first_occurrence = match data.windows(to_replace.len()).position(|window| window == to_replace.as_bytes()) {
-        Some(pos) => pos,
-        None => return Ok(()), // No occurrences found, return the original data
-    };
-    data[first_occurrence..first_occurrence+replacement_bytes.len()].copy_from_slice(replacement_bytes);
-    Ok(())
-}
-
-pub fn elongate(string: &str, character: char, length: usize, leading: bool) -> Result<String, String> {
-    if string.len() > length {
-        return Err(format!("{string} is too long, should be at most {length} characters"))
-    }
-    let mut result = string.to_string();
-    while result.len() < length {
-        if leading {
-            result.insert(0, character);
-        } else {
-            result.push(character);
-        }
-    }
-    Ok(result)
-}
-
-pub fn find_and_replace_float(buffer: &mut [u8], target: f32, replacement: f32) {
-    let buffer_len = buffer.len();
-    let new_value_bytes = replacement.to_le_bytes();
+pub const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
 
-    for i in 0..buffer_len - 4 {
-        if let Ok(bytes) = buffer[i..i + 4].try_into() {
-            let value = f32::from_le_bytes(bytes);
-            if target == value {
-                buffer[i..i + 4].copy_from_slice(&new_value_bytes);
-            }
-        }
-    }
+/// Continues a 64 bit FNV-1a hash with more bytes, starting from [`FNV_OFFSET_BASIS`]
+pub fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
 }
 
-pub fn save_as_dds(image: &RgbaImage, output_path: String, high_quality: bool) {
-    let dds_image = dds_from_image(image, if high_quality { ImageFormat::BC7RgbaUnorm } else { ImageFormat::BC1RgbaUnorm }, Quality::Slow, Mipmaps::Disabled).expect("Failed to convert to dds");
-    let mut writer = BufWriter::new(File::create(output_path).unwrap());
-    dds_image.write(&mut writer).unwrap();
+/// Derives a stable identifier from a display name: its first few alphanumeric characters, filled up with an FNV-1a hash of the whole name
+pub fn identifier(name: &str) -> String {
+    let hash = fnv1a(FNV_OFFSET_BASIS, name.as_bytes());
+    let mut identifier: String = name.chars().filter(|c| c.is_ascii_alphanumeric()).take(4).collect();
+    let hash = format!("{:016X}", hash ^ (hash >> 32));
+    identifier.push_str(&hash[hash.len() + identifier.len() - IDENTIFIER_LENGTH..]);
+    identifier
 }
 
 pub fn user_input(text: &str) -> String {
     print!("{}", text);
-    stdout().flush().unwrap();
+    let _ = stdout().flush();
     let mut result = String::new();
-    stdin().read_line(&mut result).unwrap();
+    // A closed stdin counts as an empty answer
+    let _ = stdin().read_line(&mut result);
     result.trim().to_string()
 }
 
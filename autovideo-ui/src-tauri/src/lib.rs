@@ -1,69 +1,58 @@
-#![allow(clippy::too_many_arguments)]
-
 use std::path::PathBuf;
-use autovideo_core::{Mode, ScriptInfo, count_esp_placeholders, process_videos};
+use std::sync::Mutex;
+use autovideo_core::{AutovideoError, CancellationToken, ConversionJob, JobOptions, Mode, process_videos, TextureEstimate, TextureFormat, TextureOptions};
 use serde::Serialize;
-use tauri::{Window, Emitter};
+use tauri::{Emitter, State, Window};
 
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 
-#[derive(Clone, Serialize)]
-struct Progress {
-    current: usize,
-    max: usize,
+/// Error sent to the frontend, carrying the readable message next to the error kind and its fields
+#[derive(Serialize)]
+struct ConvertError {
+    message: String,
+    #[serde(flatten)]
+    error: AutovideoError,
+}
+
+impl From<AutovideoError> for ConvertError {
+    fn from(error: AutovideoError) -> Self {
+        ConvertError { message: error.to_string(), error }
+    }
 }
 
+/// Token of the conversion that is running, if any
+#[derive(Default)]
+struct RunningConversion(Mutex<Option<CancellationToken>>);
+
 #[tauri::command]
-async fn convert_files(
-    window: Window,
-    inputs: Vec<PathBuf>,
-    input_esp: Option<PathBuf>,
-    input_esp_drive_in: Option<PathBuf>,
-    mod_name: String,
-    input_framerate: u32,
-    short_names: bool,
-    video_name: Option<String>,
-    size: u32,
-    keep_aspect_ratio: bool,
-    script_info: Option<ScriptInfo>,
-    high_quality: bool
-) -> Result<(), String> {
-    let mut progress = Progress {
-        current: 0,
-        max: if keep_aspect_ratio {
-            inputs.len() * 3
-        } else {
-            inputs.len() * 2
-        },
-    };
+async fn convert_files(window: Window, running: State<'_, RunningConversion>, job: ConversionJob) -> Result<PathBuf, ConvertError> {
+    let cancel = CancellationToken::new();
+    *running.0.lock().unwrap() = Some(cancel.clone());
     
-    process_videos(
-        inputs,
-        input_esp,
-        input_esp_drive_in,
-        mod_name,
-        input_framerate,
-        short_names,
-        video_name,
-        size,
-        keep_aspect_ratio,
-        script_info.is_some(),
-        script_info,
-        Mode::UiMode,
-        || {
-            progress.current += 1;
-            window.emit("listener", progress.clone()).unwrap();
-        },
-        high_quality
-    )?;
+    let result = process_videos(&job, Mode::UiMode, &cancel, |event| {
+        window.emit("listener", event).unwrap();
+    });
+    *running.0.lock().unwrap() = None;
+    result?;
     
-    Ok(())
+    Ok(job.options.output.output_dir)
 }
 
+/// Stops the running conversion, which then fails with a `cancelled` error
+#[tauri::command]
+fn cancel_conversion(running: State<'_, RunningConversion>) {
+    if let Some(cancel) = running.0.lock().unwrap().as_ref() {
+        cancel.cancel();
+    }
+}
 
+/// Size and encoding time of a grid texture in every format, with the size, layout and texture settings of the options
 #[tauri::command]
-async fn count_placeholders(esp: PathBuf) -> u32 {
-    count_esp_placeholders(esp)
+fn texture_estimates(options: JobOptions) -> Vec<(TextureFormat, TextureEstimate)> {
+    TextureFormat::ALL.into_iter().map(|format| {
+        let options = JobOptions { texture: TextureOptions { format, ..options.texture }, ..options.clone() };
+        (format, options.texture_estimate())
+    }).collect()
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -71,7 +60,8 @@ pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_opener::init())
-        .invoke_handler(tauri::generate_handler![convert_files, count_placeholders])
+        .manage(RunningConversion::default())
+        .invoke_handler(tauri::generate_handler![convert_files, cancel_conversion, texture_estimates])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
@@ -14,80 +14,154 @@ document.getElementById('close-button')?.addEventListener('click', () => appWind
 
 window.addEventListener('contextmenu', e => e.preventDefault())
 
+type ConvertError = { kind: string, message: string }
+
+type Count = { done: number, total: number | null }
+type ProgressEvent = {
+    stage: 'extracting' | 'reading' | 'encodingGrids' | 'done' | 'writingMeshes' | 'writingPlugin' | 'packingArchives' | 'writingInstaller',
+    video: number | null,
+    frames: Count | null,
+    grids: Count | null,
+}
+
+/** Part of a video that is converted, extracting takes about as long as encoding the grids with fast settings */
+const videoFraction = ({ stage, frames, grids }: ProgressEvent) => {
+    const part = (count: Count | null) => count?.total ? Math.min(count.done / count.total, 1) : 0
+    switch (stage) {
+        case 'extracting': return 0.4 * part(frames)
+        case 'reading':
+        case 'encodingGrids': return 0.4 + 0.6 * part(grids)
+        default: return 1
+    }
+}
+
+const formatEta = (seconds: number) => seconds < 60 ? `${Math.ceil(seconds)} s left` : `${Math.ceil(seconds / 60)} min left`
+
+type TextureFormat = 'bc1' | 'bc3' | 'bc7' | 'uncompressed'
+type TextureEstimate = { bytes: number, seconds: number }
+
+const textureFormats: Record<TextureFormat, string> = {
+    bc1: 'BC1',
+    bc3: 'BC3',
+    bc7: 'BC7',
+    uncompressed: 'Uncompressed',
+}
+
+const errorTitles: Record<string, string> = {
+    ffmpegMissing: 'FFmpeg not found',
+    videoTooLong: 'Video too long',
+    duplicateName: 'Duplicate video name',
+    nameCollision: 'Duplicate video name',
+    invalidEsp: 'Invalid esp',
+    invalidManifest: 'Invalid manifest',
+}
+
 function App() {
     const [active, setActive] = useState(false)
     const [progress, setProgress] = useState({current: 0, max: 1, isErrored: false})
+    const [eta, setEta] = useState<number>()
 
     const [modName, setModName] = useState('')
     const [selectedGenerate, setSelectedGenerate] = useState<'esp' | 'script'>('esp')
     const [size, setSize] = useState(512)
+    const [screenSizes, setScreenSizes] = useState<{ television?: number, projector?: number, drive_in?: number }>({})
     const [fps, setFps] = useState(10)
-    const [highQuality, setHighQuality] = useState(false)
+    const [textureFormat, setTextureFormat] = useState<TextureFormat>('bc1')
+    const [encoderSpeed, setEncoderSpeed] = useState<'fast' | 'normal' | 'slow'>('slow')
+    const [mipmaps, setMipmaps] = useState(false)
+    const [estimates, setEstimates] = useState<[TextureFormat, TextureEstimate][]>([])
 
     const [inputs, setInputs] = useState<string[]>([])
     const [esp, setEsp] = useState<string>()
     const [desp, setDesp] = useState<string>()
+    const [outputDir, setOutputDir] = useState<string>()
 
     const [espName, setEspName] = useState('')
     const [tvRecord, setTvRecord] = useState('')
     const [prRecord, setPrRecord] = useState('')
     const [driveInEspName, setDriveinEspName] = useState('')
 
-    const [shortNames, setShortNames] = useState(true)
-    const [keepAspectRatio, setKeepAspectRatio] = useState(true)
+    const [archives, setArchives] = useState(false)
+    const [fomod, setFomod] = useState(false)
+    const [aspect, setAspect] = useState<'stretch' | 'pad' | 'crop' | 'smart_crop'>('pad')
+
+    useEffect(() => {
+        invoke<[TextureFormat, TextureEstimate][]>('texture_estimates', {
+            options: { size, texture: { speed: encoderSpeed, mipmaps } }
+        }).then(setEstimates)
+    }, [size, encoderSpeed, mipmaps])
 
     const inputValid = inputs.length > 0
-        && modName.length > 0
+        && modName.trim().length > 0
         && (selectedGenerate === 'script' ? (espName.length > 0 && tvRecord.length > 0 && prRecord.length > 0) : true)
 
-    useEffect(() => {
-        (async () => {
-            if (selectedGenerate == 'esp') {
-                for (const path of [esp, desp]) {
-                    const amount: number = !!path ? await invoke('count_placeholders', {esp: path}) : 10
-                    if (inputs.length > amount) {
-                        await message(`You have selected ${inputs.length} video(s) but only have ${amount} placeholders${!!path ? ` left in "${path}"` : ''}! Please select less videos or generate a FO4Edit script instead!`, { title: 'Too many videos!', kind: 'warning' })
-                        break
-                    }
-                }
-            }
-        })()
-    }, [esp, desp, inputs, selectedGenerate])
-
     const onStart = async () => {
         setActive(true)
         setProgress({current: 0, max: 1, isErrored: false})
-        const unlisten = await listen<{ current: number, max: number }>('listener', (event) => setProgress({...event.payload, isErrored: false}))
+        setEta(undefined)
+        // Videos are converted side by side, each one adds its own part. Writing the mod takes the last step
+        const fractions = inputs.map(() => 0)
+        const max = inputs.length + 1
+        const started = Date.now()
+        const unlisten = await listen<ProgressEvent>('listener', ({ payload }) => {
+            if (payload.video !== null) {
+                fractions[payload.video] = videoFraction(payload)
+            } else {
+                fractions.fill(1)
+            }
+            const current = fractions.reduce((sum, fraction) => sum + fraction, 0)
+            setProgress({current, max, isErrored: false})
+            const elapsed = (Date.now() - started) / 1000
+            setEta(current > 0 && elapsed > 5 ? elapsed * (max - current) / current : undefined)
+        })
         try {
-            await invoke('convert_files', {
-                inputs,
-                inputEsp: esp,
-                inputEspDriveIn: desp,
-                modName,
-                inputFramerate: fps,
-                shortNames,
-                videoName: undefined,
-                size,
-                keepAspectRatio,
-                scriptInfo: selectedGenerate === 'script' ? {
-                    esp_name: espName,
-                    tv_record: tvRecord,
-                    pr_record: prRecord,
-                    di_esp_name: driveInEspName
-                } : undefined,
-                highQuality
+            const output = await invoke<string>('convert_files', {
+                job: {
+                    mod_name: modName,
+                    videos: inputs.map(path => ({ path })),
+                    options: {
+                        input_esp: esp,
+                        input_esp_drive_in: desp,
+                        framerate: fps,
+                        size,
+                        screen_sizes: screenSizes,
+                        aspect: { mode: aspect },
+                        script_info: selectedGenerate === 'script' ? {
+                            esp_name: espName,
+                            tv_record: tvRecord,
+                            pr_record: prRecord,
+                            di_esp_name: driveInEspName
+                        } : undefined,
+                        texture: { format: textureFormat, speed: encoderSpeed, mipmaps },
+                        output: { output_dir: outputDir, archives, fomod }
+                    }
+                }
             })
-            await revealItemInDir('./output')
+            setProgress({current: 1, max: 1, isErrored: false})
+            setEta(undefined)
+            await revealItemInDir(output)
         } catch (err) {
+            setEta(undefined)
+            const error = err as Partial<ConvertError>
+            if (error.kind === 'cancelled') {
+                setProgress({current: 0, max: 1, isErrored: false})
+                unlisten()
+                setActive(false)
+                return
+            }
             setProgress({current: 1, max: 1, isErrored: true})
-            await message(String(err), { title: 'Error', kind: 'error' })
+            let text = error.message ?? String(err)
+            if (error.kind === 'ffmpegMissing') {
+                text += '\nMake sure ffmpeg is installed and added to your PATH'
+            }
+            await message(text, { title: errorTitles[error.kind ?? ''] ?? 'Error', kind: 'error' })
         }
         unlisten()
         setActive(false)
     }
 
     return (
-        <div className="window-body has-space" style={{height: 287, display: 'flex', flexDirection: 'column', justifyContent: 'space-between'}}>
+        <div className="window-body has-space" style={{height: 331, display: 'flex', flexDirection: 'column', justifyContent: 'space-between'}}>
             <div style={{display: 'flex', justifyContent: 'space-between'}}>
                 <div>
                     <div className="field-row-stacked" style={{marginBottom: 6}}>
@@ -97,8 +171,8 @@ function App() {
                             id="mod-name"
                             type="text"
                             value={modName}
-                            onChange={e => {if (e.target.value.length <= 10) setModName(e.target.value)}}
-                            title={`Name of the mod. Can't be longer than 10 characters!`}
+                            onChange={e => setModName(e.target.value)}
+                            title="Name of the mod, shown in-game on the holotapes and reels"
                             disabled={active}
                         />
                     </div>
@@ -108,7 +182,16 @@ function App() {
                         htmlId="input-path"
                         name="Input Path*"
                         options={{multiple: true, filters: [{name: 'Video', extensions: ['mp4', 'mkv', 'webm', 'avi', 'gif', 'webp']}]}}
-                        tooltip={`Path(s) to video(s) to convert.\nNames of video files will be used to name the holotapes.\nVideo names can't be longer than 10 characters!`}
+                        tooltip={`Path(s) to video(s) to convert.\nNames of video files will be used to name the holotapes.`}
+                        disabled={active}
+                    />
+                    <PathSelector
+                        value={outputDir ? [outputDir] : []}
+                        onConfirm={value => setOutputDir(value.length > 0 ? value[0] : undefined)}
+                        htmlId="output-path"
+                        name="Output Path"
+                        options={{directory: true}}
+                        tooltip={`OPTIONAL\nFolder to write the mod files to, e.g. a mod manager staging folder\nDefaults to the output folder next to the app`}
                         disabled={active}
                     />
                     {selectedGenerate === 'esp' && <>
@@ -118,7 +201,7 @@ function App() {
                             htmlId="esp-path"
                             name="ESP Path"
                             options={{filters: [{name: 'CreationKit ESP', extensions: ['esp']}]}}
-                            tooltip={`OPTIONAL\nPath to existing esp file to append to that one instead of generating a new one\nThis will create a copy in the output folder and not directly edit given one\nOnly works on ESPs that still have placeholders left`}
+                            tooltip={`OPTIONAL\nPath to existing esp file to append to that one instead of generating a new one\nThis will create a copy in the output folder and not directly edit given one`}
                             disabled={active}
                         />
                         <PathSelector
@@ -127,7 +210,7 @@ function App() {
                             htmlId="desp-path"
                             name="DriveIn ESP Path"
                             options={{filters: [{name: 'CreationKit ESP', extensions: ['esp']}]}}
-                            tooltip={`OPTIONAL\nPath to existing DriveIn esp file to append to that one instead of generating a new one\nThis will create a copy in the output folder and not directly edit given one\nOnly works on ESPs that still have placeholders left`}
+                            tooltip={`OPTIONAL\nPath to existing DriveIn esp file to append to that one instead of generating a new one\nThis will create a copy in the output folder and not directly edit given one`}
                             disabled={active}
                         />
                     </>}
@@ -186,23 +269,59 @@ function App() {
                             />
                         </div>
                     </div>
+                    <div style={{display: 'flex', gap: 10, marginTop: 4}}>
+                        {([['television', 'TV'], ['projector', 'Projector'], ['drive_in', 'DriveIn']] as const).map(([screen, label]) => (
+                            <div key={screen}>
+                                <label htmlFor={`${screen}-size-select`} style={{marginRight: 5}}>{label}</label>
+                                <select disabled={active} id={`${screen}-size-select`} value={screenSizes[screen] ?? ''} onChange={e => setScreenSizes(sizes => ({...sizes, [screen]: e.target.value ? Number(e.target.value) : undefined}))} title={`Size of the frames on this screen\nScreens with a size of their own get separate textures, e.g. a sharp DriveIn next to small TVs`}>
+                                    <option value="">Same</option>
+                                    {[128, 256, 512, 1024].map(option => (
+                                        <option key={option}>{option}</option>
+                                    ))}
+                                </select>
+                            </div>
+                        ))}
+                    </div>
                     <div style={{marginTop: 4}}>
-                        <label htmlFor="quality-select" style={{marginRight: 5}}>Quality</label>
-                        <select disabled={active} id="quality-select" value={highQuality ? 'High' : 'Low'} onChange={e => setHighQuality(e.target.value === 'High')} title="High Quality will result in better visuals but double the filesize and take longer to process">
-                            {[false, true].map(option => (
-                                <option key={option ? 'high' : 'low'}>{option ? 'High' : 'Low'}</option>
+                        <label htmlFor="format-select" style={{marginRight: 5}}>Format</label>
+                        <select disabled={active} id="format-select" value={textureFormat} onChange={e => setTextureFormat(e.target.value as TextureFormat)} title={`Compression of the grid textures, with the size and encoding time of a single grid\nBC7 looks best but is much slower to encode than BC1 and twice the size`}>
+                            {estimates.map(([format, estimate]) => (
+                                <option key={format} value={format}>
+                                    {textureFormats[format]} (~{(estimate.bytes / 1048576).toFixed(1)} MB, {estimate.seconds.toFixed(1)} s)
+                                </option>
                             ))}
                         </select>
                     </div>
+                    <div style={{marginTop: 4}}>
+                        <label htmlFor="speed-select" style={{marginRight: 5}}>Encoder</label>
+                        <select disabled={active} id="speed-select" value={encoderSpeed} onChange={e => setEncoderSpeed(e.target.value as typeof encoderSpeed)} title="Fast encoding is good enough for test builds, slow encoding looks best">
+                            <option value="fast">Fast</option>
+                            <option value="normal">Normal</option>
+                            <option value="slow">Slow</option>
+                        </select>
+                    </div>
                     <fieldset>
                         <legend>Options</legend>
                         <div className="field-row">
-                            <input checked={shortNames} onChange={() => setShortNames(b => !b)} type="checkbox" id="short-names" disabled={active} />
-                            <label title={`Enable to not give a warning for names being too long and to automatically cut them shorter\nWill not work if multiple videos start with the same 10 characters`} htmlFor="short-names">Short names</label>
+                            <label htmlFor="aspect-select">Aspect</label>
+                            <select disabled={active} id="aspect-select" value={aspect} onChange={e => setAspect(e.target.value as typeof aspect)} title={`How to fit videos into the 4:3 FO4 TVs\nStretch makes square frames, the other modes make 4:3 frames with more of them per texture`}>
+                                <option value="pad">Pad</option>
+                                <option value="crop">Crop</option>
+                                <option value="smart_crop">Smart crop</option>
+                                <option value="stretch">Stretch</option>
+                            </select>
+                        </div>
+                        <div className="field-row">
+                            <input checked={mipmaps} onChange={() => setMipmaps(b => !b)} type="checkbox" id="mipmaps" disabled={active} />
+                            <label title={`Generates mipmaps so screens seen from afar flicker less\nMakes the textures a third larger`} htmlFor="mipmaps">Mipmaps</label>
+                        </div>
+                        <div className="field-row">
+                            <input checked={archives} onChange={() => setArchives(b => !b)} type="checkbox" id="archives" disabled={active} />
+                            <label title={`Packs the textures, meshes and sounds into BA2 archives named after the esp instead of loose files`} htmlFor="archives">Pack into BA2</label>
                         </div>
                         <div className="field-row">
-                            <input checked={keepAspectRatio} onChange={() => setKeepAspectRatio(b => !b)} type="checkbox" id="keep-aspect-ratio" disabled={active} />
-                            <label title="Will automatically refit input to 4:3 aspect ratio. (Which fits FO4 TVs better)" htmlFor="keep-aspect-ratio">Keep aspect ratio</label>
+                            <input checked={fomod} onChange={() => setFomod(b => !b)} type="checkbox" id="fomod" disabled={active} />
+                            <label title={`Writes a FOMOD installer offering the DriveIn esp and every texture size built into the output folder`} htmlFor="fomod">FOMOD installer</label>
                         </div>
                     </fieldset>
                     {selectedGenerate === 'script' && <div className="field-row-stacked">
@@ -215,7 +334,10 @@ function App() {
                 <div role="progressbar" className={`${active ? (progress.current === 0 ? 'marquee' : 'animate') : ''} ${progress.isErrored ? 'error' : ''}`} style={{width: '100%'}}>
                     <div style={{width: `${progress.current/progress.max*100}%`}}></div>
                 </div>
-                <button onClick={onStart} disabled={!inputValid || active}>START</button>
+                {active && eta !== undefined && <span style={{whiteSpace: 'nowrap'}}>{formatEta(eta)}</span>}
+                {active
+                    ? <button onClick={() => invoke('cancel_conversion')}>CANCEL</button>
+                    : <button onClick={onStart} disabled={!inputValid}>START</button>}
             </div>
         </div>
     );